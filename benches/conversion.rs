@@ -0,0 +1,87 @@
+// Compares the conversions between images and tensors with the per-element
+// versions they replaced. Run with `cargo bench --bench conversion`, and add
+// `--features parallel` to see what preparing rows on all cores gives.
+use bodypix::image::{DynamicImage, GenericImageView, Pixel, Rgb, RgbImage};
+use bodypix::{prepare_input, InputConfig, MobileNetNormalization, Normalization, Segments};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use tensorflow::Tensor;
+
+const IMAGE_SIZES: [(u32, u32); 2] = [(640, 480), (1920, 1080)];
+
+// Output grids of a 1080p image at stride 16, and of a 4K image at stride 8
+const GRID_SIZES: [(u64, u64); 2] = [(121, 68), (481, 271)];
+
+fn test_image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    }))
+}
+
+// How the input used to be built, one pixel at a time through the DynamicImage
+fn per_pixel_input(image: &DynamicImage, normalization: &dyn Normalization) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let mut values = Vec::with_capacity(((width + 1) * (height + 1) * 3) as usize);
+    for y in 0..height + 1 {
+        for x in 0..width + 1 {
+            let pixel = image.get_pixel(x.min(width - 1), y.min(height - 1)).to_rgb();
+            values.extend_from_slice(&normalization.normalize(pixel.0));
+        }
+    }
+    values
+}
+
+// How the mask used to be read, one element at a time in column-major order
+fn per_element_segments(t: &Tensor<f32>) -> Vec<f32> {
+    let height = t.dims()[1] as usize;
+    let width = t.dims()[2] as usize;
+    let mut values = vec![0f32; width * height];
+    for x in 0..width {
+        for y in 0..height {
+            let seg_val = t.get(&[0, y as u64, x as u64, 0]);
+            values[x + y * width] = 1. / (1. + (-seg_val).exp());
+        }
+    }
+    values
+}
+
+fn input(c: &mut Criterion) {
+    let mut group = c.benchmark_group("input");
+    let config = InputConfig::default();
+    let normalization = MobileNetNormalization;
+
+    for &(width, height) in IMAGE_SIZES.iter() {
+        let image = test_image(width, height);
+        let size = format!("{}x{}", width, height);
+
+        group.bench_with_input(BenchmarkId::new("per_pixel", &size), &image, |b, image| {
+            b.iter(|| per_pixel_input(black_box(image), &normalization))
+        });
+
+        let mut values = Vec::new();
+        group.bench_with_input(BenchmarkId::new("rows", &size), &image, |b, image| {
+            b.iter(|| prepare_input(black_box(image), &config, &normalization, &mut values).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn segments(c: &mut Criterion) {
+    let mut group = c.benchmark_group("segments");
+
+    for &(width, height) in GRID_SIZES.iter() {
+        let values: Vec<f32> = (0..width * height).map(|i| (i % 17) as f32 - 8.).collect();
+        let t = Tensor::new(&[1, height, width, 1]).with_values(&values).unwrap();
+        let size = format!("{}x{}", width, height);
+
+        group.bench_with_input(BenchmarkId::new("per_element", &size), &t, |b, t| {
+            b.iter(|| per_element_segments(black_box(t)))
+        });
+        group.bench_with_input(BenchmarkId::new("slice", &size), &t, |b, t| {
+            b.iter(|| Segments::from_tensor(16, width as usize * 16, height as usize * 16, black_box(t)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, input, segments);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+//! The inference runtimes a `BodyPix` can run its model on.
+
+#[cfg(feature = "tensorflow")]
+mod tensorflow;
+#[cfg(feature = "tract")]
+mod tract;
+
+use std::ops::Deref;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::options::BodyPixOptions;
+
+#[cfg(feature = "tensorflow")]
+pub use self::tensorflow::TensorflowBackend;
+#[cfg(feature = "tract")]
+pub use self::tract::TractBackend;
+
+// Runs a BodyPix model. Inputs and outputs are addressed by operation name,
+// as GraphOperations finds them, so the same lookup works for every runtime.
+pub trait SegmentationBackend: Send + Sync {
+    fn load(path: &Path, options: &BodyPixOptions) -> Result<Self>
+    where
+        Self: Sized;
+
+    // Operations that can be fed or fetched, leaving out the weights
+    fn operation_names(&self) -> Vec<String>;
+
+    // Operations the model takes its input from
+    fn input_names(&self) -> Vec<String>;
+
+    // Runs the model on a [batch, height, width, 3] input and fetches the named
+    // outputs, in the same order. The input is written by fill, straight into
+    // the memory the runtime reads it from.
+    fn run(
+        &self,
+        input_name: &str,
+        dims: [usize; 4],
+        fill: &mut dyn FnMut(&mut [f32]) -> Result<()>,
+        output_names: &[&str],
+    ) -> Result<Vec<OutputTensor>>;
+}
+
+// An output of the model, as row-major values of the shape in dims
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTensor {
+    dims: Vec<u64>,
+    values: Vec<f32>,
+}
+
+impl OutputTensor {
+    pub fn new(dims: Vec<u64>, values: Vec<f32>) -> OutputTensor {
+        assert_eq!(
+            dims.iter().product::<u64>() as usize,
+            values.len(),
+            "{} values don't fit shape {:?}",
+            values.len(),
+            dims
+        );
+        OutputTensor { dims, values }
+    }
+
+    pub fn dims(&self) -> &[u64] {
+        &self.dims
+    }
+}
+
+impl Deref for OutputTensor {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.values
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tensorflow::{Graph, ImportGraphDefOptions, Operation, SavedModelBundle, Session, SessionRunArgs, Tensor};
+
+use crate::backend::{OutputTensor, SegmentationBackend};
+use crate::error::{BodyPixError, Result};
+use crate::operations::GraphOperations;
+use crate::options::BodyPixOptions;
+
+// Frozen graphs and SavedModels, run by libtensorflow
+pub struct TensorflowBackend {
+    graph: Graph,
+    session: Session,
+    // The last input tensor, reused while the input shape stays the same
+    input_buffer: Mutex<Option<Tensor<f32>>>,
+}
+
+impl TensorflowBackend {
+    pub fn from_graph_def(graph_def: &[u8], options: &BodyPixOptions) -> Result<TensorflowBackend> {
+        let mut graph = Graph::new();
+        graph
+            .import_graph_def_with_results(graph_def, &ImportGraphDefOptions::new())
+            .map_err(BodyPixError::InvalidGraph)?;
+        let session = Session::new(&options.session_options()?, &graph).map_err(BodyPixError::Session)?;
+
+        Ok(TensorflowBackend {
+            graph,
+            session,
+            input_buffer: Mutex::new(None),
+        })
+    }
+
+    // Loads a SavedModel directory, with the operations named by one of its signatures
+    pub(crate) fn from_saved_model(
+        dir: &Path,
+        tag: &str,
+        signature: &str,
+        options: &BodyPixOptions,
+    ) -> Result<(TensorflowBackend, GraphOperations)> {
+        std::fs::metadata(dir.join("saved_model.pb")).map_err(|source| BodyPixError::MissingFile {
+            path: dir.to_path_buf(),
+            source,
+        })?;
+
+        let mut graph = Graph::new();
+        let bundle = SavedModelBundle::load(&options.session_options()?, &[tag], &mut graph, dir)
+            .map_err(BodyPixError::InvalidGraph)?;
+
+        let signatures = bundle.meta_graph_def().signatures();
+        let signature_def = signatures
+            .get(signature)
+            .ok_or_else(|| BodyPixError::UnknownOperation {
+                role: "signature".to_string(),
+                tried: vec![signature.to_string()],
+                available: signatures.keys().cloned().collect(),
+            })?;
+        let operations = GraphOperations::from_signature(signature_def)?;
+
+        let backend = TensorflowBackend {
+            graph,
+            session: bundle.session,
+            input_buffer: Mutex::new(None),
+        };
+        Ok((backend, operations))
+    }
+
+    fn operation(&self, name: &str) -> Result<Operation> {
+        self.graph
+            .operation_by_name_required(name)
+            .map_err(|_| BodyPixError::MissingOperation(name.to_string()))
+    }
+
+    fn operations_where(&self, keep: impl Fn(&str) -> bool) -> Vec<String> {
+        self.graph
+            .operation_iter()
+            .filter(|op| op.op_type().map(|op_type| keep(&op_type)).unwrap_or(false))
+            .filter_map(|op| op.name().ok())
+            .collect()
+    }
+
+    // The reused input tensor if it has the right shape, which it has for
+    // every frame of a video, or else a new one
+    fn take_input(&self, dims: [u64; 4]) -> Tensor<f32> {
+        let buffer = self.input_buffer.lock().ok().and_then(|mut buffer| buffer.take());
+        match buffer {
+            Some(input) if input.dims() == &dims[..] => input,
+            _ => Tensor::new(&dims),
+        }
+    }
+
+    fn recycle_input(&self, input: Tensor<f32>) {
+        if let Ok(mut buffer) = self.input_buffer.lock() {
+            *buffer = Some(input);
+        }
+    }
+
+    fn run_tensor(&self, input_name: &str, input: &Tensor<f32>, output_names: &[&str]) -> Result<Vec<OutputTensor>> {
+        let mut args: SessionRunArgs = SessionRunArgs::new();
+        args.add_feed(&self.operation(input_name)?, 0, input);
+
+        let mut tokens = Vec::with_capacity(output_names.len());
+        for name in output_names {
+            tokens.push(args.request_fetch(&self.operation(name)?, 0));
+        }
+
+        self.session.run(&mut args).map_err(BodyPixError::Session)?;
+
+        tokens
+            .into_iter()
+            .map(|token| {
+                let output: Tensor<f32> = args.fetch(token).map_err(BodyPixError::Session)?;
+                Ok(OutputTensor::from(&output))
+            })
+            .collect()
+    }
+}
+
+impl SegmentationBackend for TensorflowBackend {
+    // A frozen graph, as written by bodypix-models
+    fn load(path: &Path, options: &BodyPixOptions) -> Result<TensorflowBackend> {
+        let mut graph_def: Vec<u8> = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut graph_def))
+            .map_err(|source| BodyPixError::MissingFile {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        TensorflowBackend::from_graph_def(&graph_def, options)
+    }
+
+    fn operation_names(&self) -> Vec<String> {
+        self.operations_where(|op_type| op_type != "Const")
+    }
+
+    fn input_names(&self) -> Vec<String> {
+        self.operations_where(|op_type| op_type == "Placeholder")
+    }
+
+    fn run(
+        &self,
+        input_name: &str,
+        dims: [usize; 4],
+        fill: &mut dyn FnMut(&mut [f32]) -> Result<()>,
+        output_names: &[&str],
+    ) -> Result<Vec<OutputTensor>> {
+        let mut input = self.take_input([dims[0] as u64, dims[1] as u64, dims[2] as u64, dims[3] as u64]);
+        let outputs = fill(&mut input).and_then(|()| self.run_tensor(input_name, &input, output_names));
+        self.recycle_input(input);
+        outputs
+    }
+}
+
+impl From<&Tensor<f32>> for OutputTensor {
+    fn from(t: &Tensor<f32>) -> OutputTensor {
+        OutputTensor::new(t.dims().to_vec(), t.to_vec())
+    }
+}
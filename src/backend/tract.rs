@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tract_onnx::prelude::*;
+
+use crate::backend::{OutputTensor, SegmentationBackend};
+use crate::error::{BodyPixError, Result};
+use crate::options::BodyPixOptions;
+
+type Plan = TypedRunnableModel<TypedModel>;
+type PlanKey = (Vec<usize>, Vec<String>); // Input shape and output names
+
+// ONNX exports of the models, run by tract in pure Rust, so a binary needs no
+// native libraries. Converters write tensor names like "float_segments:0",
+// which are found without the ":0" like the Tensorflow operation names.
+pub struct TractBackend {
+    model: InferenceModel,
+    outlets: HashMap<String, OutletId>,
+    // tract optimizes a model for one input shape and set of outputs, so the
+    // plans are kept for the shapes seen, like every frame of a video
+    plans: Mutex<HashMap<PlanKey, Arc<Plan>>>,
+}
+
+// More input shapes than this, and the plans are rebuilt as needed
+const MAX_PLANS: usize = 16;
+
+fn backend_error(err: TractError) -> BodyPixError {
+    BodyPixError::Backend(err.into())
+}
+
+impl TractBackend {
+    fn plan(&self, input_name: &str, dims: [usize; 4], output_names: &[&str]) -> Result<Arc<Plan>> {
+        let key: PlanKey = (dims.to_vec(), output_names.iter().map(|name| name.to_string()).collect());
+        if let Some(plan) = self.plans.lock().ok().and_then(|plans| plans.get(&key).cloned()) {
+            return Ok(plan);
+        }
+
+        let input = self.outlet(input_name)?;
+        let outputs = output_names
+            .iter()
+            .map(|name| self.outlet(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut model = self.model.clone();
+        model.set_input_outlets(&[input]).map_err(backend_error)?;
+        model
+            .set_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), dims.to_vec()))
+            .map_err(backend_error)?;
+        model.set_output_outlets(&outputs).map_err(backend_error)?;
+        // Exported shapes have symbols for the image size, which would clash with the real one
+        for output in 0..outputs.len() {
+            model.set_output_fact(output, InferenceFact::default()).map_err(backend_error)?;
+        }
+        let plan = Arc::new(
+            model
+                .into_optimized()
+                .and_then(|model| model.into_runnable())
+                .map_err(backend_error)?,
+        );
+
+        if let Ok(mut plans) = self.plans.lock() {
+            if plans.len() >= MAX_PLANS {
+                plans.clear();
+            }
+            plans.insert(key, plan.clone());
+        }
+        Ok(plan)
+    }
+
+    fn outlet(&self, name: &str) -> Result<OutletId> {
+        self.outlets
+            .get(name)
+            .copied()
+            .ok_or_else(|| BodyPixError::MissingOperation(name.to_string()))
+    }
+}
+
+impl SegmentationBackend for TractBackend {
+    fn load(path: &Path, _options: &BodyPixOptions) -> Result<TractBackend> {
+        std::fs::metadata(path).map_err(|source| BodyPixError::MissingFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let model = tract_onnx::onnx().model_for_path(path).map_err(backend_error)?;
+
+        // Nodes by name, and their outputs by tensor name
+        let mut outlets = HashMap::new();
+        for node in model.nodes() {
+            if node.op.name() == "Const" {
+                continue;
+            }
+            outlets.insert(node.name.trim_end_matches(":0").to_string(), OutletId::new(node.id, 0));
+            for slot in 0..node.outputs.len() {
+                let outlet = OutletId::new(node.id, slot);
+                if let Some(label) = model.outlet_label(outlet) {
+                    outlets.entry(label.trim_end_matches(":0").to_string()).or_insert(outlet);
+                }
+            }
+        }
+
+        Ok(TractBackend {
+            model,
+            outlets,
+            plans: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn operation_names(&self) -> Vec<String> {
+        self.outlets.keys().cloned().collect()
+    }
+
+    fn input_names(&self) -> Vec<String> {
+        let inputs = match self.model.input_outlets() {
+            Ok(inputs) => inputs,
+            Err(_) => return Vec::new(),
+        };
+        self.outlets
+            .iter()
+            .filter(|(_, outlet)| inputs.contains(outlet))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn run(
+        &self,
+        input_name: &str,
+        dims: [usize; 4],
+        fill: &mut dyn FnMut(&mut [f32]) -> Result<()>,
+        output_names: &[&str],
+    ) -> Result<Vec<OutputTensor>> {
+        let plan = self.plan(input_name, dims, output_names)?;
+
+        let mut input = Tensor::zero::<f32>(&dims).map_err(backend_error)?;
+        fill(input.as_slice_mut::<f32>().map_err(backend_error)?)?;
+        let outputs = plan.run(tvec!(input.into())).map_err(backend_error)?;
+
+        outputs
+            .iter()
+            .map(|output| {
+                let view = output.to_array_view::<f32>().map_err(backend_error)?;
+                let dims = view.shape().iter().map(|dim| *dim as u64).collect();
+                Ok(OutputTensor::new(dims, view.iter().copied().collect()))
+            })
+            .collect()
+    }
+}
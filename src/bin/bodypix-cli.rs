@@ -0,0 +1,608 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Instant;
+
+use bodypix::image::io::Reader as ImageReader;
+use bodypix::image::DynamicImage;
+use bodypix::video::{create_video, for_each_segmented_frame, open_video, VideoSink};
+use bodypix::{
+    create_alpha_matte, create_cutout, create_silhouette, mask_to_image, save_with_alpha, AlphaCurve, Background,
+    BodyPix, BodyPixOptions, Compositor, InputConfig, InternalResolution, Mask, MaskRefiner, Matting, ModelSpec,
+    Sampling, Segments, TemporalFilter,
+};
+use clap::{App, Arg};
+use glob::Pattern;
+
+const EFFECTS: [&str; 5] = ["mask", "silhouette", "cutout", "matte", "background"];
+
+// Same image extensions as the file picker in the demo, and some videos
+const DEFAULT_PATTERNS: [&str; 10] = [
+    "*.jpg", "*.jpeg", "*.png", "*.gif", "*.y4m", "*.mp4", "*.mov", "*.mkv", "*.webm", "*.avi",
+];
+
+// Anything but .y4m is decoded and encoded through ffmpeg
+const VIDEO_EXTENSIONS: [&str; 6] = ["y4m", "mp4", "mov", "mkv", "webm", "avi"];
+
+fn main() {
+    let model_names: Vec<String> = BodyPix::models().iter().map(ModelSpec::name).collect();
+    let model_name_refs: Vec<&str> = model_names.iter().map(String::as_str).collect();
+
+    let matches = App::new("bodypix-cli")
+        .about("Segments people in image and video files, without a display")
+        .arg(
+            Arg::with_name("input")
+                .help("Image or video files, or directories to search recursively")
+                .required(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("Directory to write the results to")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("model")
+                .help("BodyPix model to segment with")
+                .short("m")
+                .long("model")
+                .takes_value(true)
+                .possible_values(&model_name_refs)
+                .default_value(model_name_refs[0]),
+        )
+        .arg(
+            Arg::with_name("models-dir")
+                .help("Directory containing the converted models")
+                .long("models-dir")
+                .takes_value(true)
+                .default_value("assets/models"),
+        )
+        .arg(
+            Arg::with_name("onnx")
+                .help("ONNX export of the model to run with tract instead, of the type --model names")
+                .long("onnx")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("internal-resolution")
+                .help("Size the images are segmented at: full, high, medium, low, a scale like 0.6, or WIDTHxHEIGHT")
+                .short("r")
+                .long("internal-resolution")
+                .takes_value(true)
+                .default_value("full"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .help("Threads Tensorflow uses within each operation [default: all cores]")
+                .long("threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("batch-size")
+                .help("Number of images to segment in one run, which is faster on CPU but uses more memory")
+                .long("batch-size")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("effect")
+                .help("Images to write for each input")
+                .short("e")
+                .long("effect")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .possible_values(&EFFECTS)
+                .default_value("mask"),
+        )
+        .arg(
+            Arg::with_name("sampling")
+                .help("How the coarse mask is scaled up to the image: nearest, bilinear, bicubic, or bilateral to follow the image's edges")
+                .long("sampling")
+                .takes_value(true)
+                .possible_values(&["nearest", "bilinear", "bicubic", "bilateral"])
+                .default_value("bilinear"),
+        )
+        .arg(
+            Arg::with_name("matting")
+                .help("Refines the mask edges to follow hair and fine detail, with a guided filter, or for small images also closed-form matting")
+                .long("matting")
+                .takes_value(true)
+                .possible_values(&["guided", "closed-form"]),
+        )
+        .arg(
+            Arg::with_name("temporal-smoothing")
+                .help("Blends each video frame's mask with the frames before, from 0 to 1, to stop edges flickering")
+                .long("temporal-smoothing")
+                .takes_value(true)
+                .value_name("AMOUNT"),
+        )
+        .arg(
+            Arg::with_name("motion-radius")
+                .help("Follows movement up to this many pixels per frame when smoothing")
+                .long("motion-radius")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .requires("temporal-smoothing"),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .help("Probability above which a pixel is part of a person, for silhouettes, cutouts and refinement")
+                .long("threshold")
+                .takes_value(true)
+                .default_value("0.7"),
+        )
+        .arg(
+            Arg::with_name("erode")
+                .help("Shrinks the mask by this many pixels, dropping thin false positives")
+                .long("erode")
+                .takes_value(true)
+                .value_name("PIXELS"),
+        )
+        .arg(
+            Arg::with_name("dilate")
+                .help("Grows the mask by this many pixels, after eroding it")
+                .long("dilate")
+                .takes_value(true)
+                .value_name("PIXELS"),
+        )
+        .arg(
+            Arg::with_name("min-island")
+                .help("Drops parts of the mask smaller than this many pixels")
+                .long("min-island")
+                .takes_value(true)
+                .value_name("PIXELS"),
+        )
+        .arg(
+            Arg::with_name("max-hole")
+                .help("Fills holes in the mask up to this many pixels")
+                .long("max-hole")
+                .takes_value(true)
+                .value_name("PIXELS"),
+        )
+        .arg(
+            Arg::with_name("feather")
+                .help("Softens the mask edges with a Gaussian blur of this sigma")
+                .long("feather")
+                .takes_value(true)
+                .value_name("SIGMA"),
+        )
+        .arg(
+            Arg::with_name("matte-format")
+                .help("File format for mattes, which keep the mask as transparency")
+                .long("matte-format")
+                .takes_value(true)
+                .possible_values(&["png", "webp"])
+                .default_value("png"),
+        )
+        .arg(
+            Arg::with_name("matte-ramp")
+                .help("Probabilities where the matte goes from transparent to opaque, e.g. 0.3,0.8")
+                .long("matte-ramp")
+                .takes_value(true)
+                .use_delimiter(true)
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("background")
+                .help("New background for the \"background\" effect: an image file, a colour like #00ff00, or blur:SIGMA")
+                .short("b")
+                .long("background")
+                .takes_value(true)
+                .default_value("blur:10"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .help(
+                    "File name patterns to pick up from directories \
+                     [default: *.jpg *.jpeg *.png *.gif *.y4m *.mp4 *.mov *.mkv *.webm *.avi]",
+                )
+                .short("i")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .get_matches();
+
+    let patterns: Vec<Pattern> = match matches.values_of("include") {
+        Some(values) => values.map(Pattern::new).collect::<Result<_, _>>(),
+        None => DEFAULT_PATTERNS.iter().map(|pattern| Pattern::new(pattern)).collect(),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Invalid include pattern: {}", err);
+        exit(2);
+    });
+
+    let internal_resolution: InternalResolution =
+        matches.value_of("internal-resolution").unwrap().parse().unwrap_or_else(|err| {
+            eprintln!("Invalid internal resolution: {}", err);
+            exit(2);
+        });
+
+    let batch_size = match matches.value_of("batch-size").unwrap().parse::<usize>() {
+        Ok(size) if size > 0 => size,
+        _ => {
+            eprintln!("Invalid batch size: expected a positive number");
+            exit(2);
+        }
+    };
+
+    let mut options = BodyPixOptions::new();
+    if let Some(threads) = matches.value_of("threads") {
+        match threads.parse() {
+            Ok(threads) => options = options.with_intra_op_threads(threads),
+            Err(err) => {
+                eprintln!("Invalid thread count \"{}\": {}", threads, err);
+                exit(2);
+            }
+        }
+    }
+
+    let spec = ModelSpec::find(matches.value_of("model").unwrap()).unwrap();
+    let models_dir = Path::new(matches.value_of("models-dir").unwrap());
+    let body_pix = match matches.value_of("onnx") {
+        Some(onnx) => load_onnx(Path::new(onnx), spec),
+        None => load_spec(models_dir, spec, &options),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Failed to load model: {}", err);
+        exit(1);
+    })
+    .with_input_config(InputConfig {
+        internal_resolution,
+        ..InputConfig::default()
+    });
+
+    let mut images = Vec::new();
+    for input in matches.values_of("input").unwrap() {
+        let input = Path::new(input);
+        if input.is_dir() {
+            collect_images(input, Path::new(""), &patterns, &mut images);
+        } else {
+            images.push((input.to_path_buf(), PathBuf::new()));
+        }
+    }
+
+    let curve = match matches.values_of("matte-ramp") {
+        Some(values) => {
+            let bounds: Vec<f32> = values
+                .map(|value| {
+                    value.parse().unwrap_or_else(|err| {
+                        eprintln!("Invalid matte ramp \"{}\": {}", value, err);
+                        exit(2);
+                    })
+                })
+                .collect();
+            AlphaCurve::Ramp {
+                low: bounds[0],
+                high: bounds[1],
+            }
+        }
+        None => AlphaCurve::Linear,
+    };
+    let sampling: Sampling = matches.value_of("sampling").unwrap().parse().unwrap();
+    let threshold: f32 = parse_number(matches.value_of("threshold").unwrap(), "threshold");
+    let mut refiner: Option<MaskRefiner> = None;
+    if let Some(radius) = matches.value_of("erode") {
+        refiner = Some(refiner.unwrap_or_default().with_erosion(parse_number(radius, "erosion")));
+    }
+    if let Some(radius) = matches.value_of("dilate") {
+        refiner = Some(refiner.unwrap_or_default().with_dilation(parse_number(radius, "dilation")));
+    }
+    if let Some(area) = matches.value_of("min-island") {
+        refiner = Some(refiner.unwrap_or_default().with_min_island_area(parse_number(area, "island area")));
+    }
+    if let Some(area) = matches.value_of("max-hole") {
+        refiner = Some(refiner.unwrap_or_default().with_max_hole_area(parse_number(area, "hole area")));
+    }
+    if let Some(sigma) = matches.value_of("feather") {
+        refiner = Some(refiner.unwrap_or_default().with_feather(parse_number(sigma, "feather")));
+    }
+    let refiner = refiner.map(|refiner: MaskRefiner| refiner.with_threshold(threshold));
+    let temporal = matches.value_of("temporal-smoothing").map(|amount| {
+        let filter = TemporalFilter::new().with_smoothing(parse_number(amount, "temporal smoothing"));
+        match matches.value_of("motion-radius") {
+            Some(radius) => filter.with_motion_compensation(parse_number(radius, "motion radius")),
+            None => filter,
+        }
+    });
+    let matting = match matches.value_of("matting") {
+        Some("guided") => Some(Matting::new().with_threshold(threshold)),
+        // Closed-form matting is too slow for much more than half a megapixel
+        Some(_) => Some(Matting::new().with_threshold(threshold).with_closed_form(500_000)),
+        None => None,
+    };
+    let background = parse_background(matches.value_of("background").unwrap()).unwrap_or_else(|err| {
+        eprintln!("Invalid background: {}", err);
+        exit(2);
+    });
+    let mut output = Output {
+        dir: Path::new(matches.value_of("output").unwrap()).to_path_buf(),
+        effects: matches.values_of("effect").unwrap().collect(),
+        matte_format: matches.value_of("matte-format").unwrap(),
+        curve,
+        sampling,
+        threshold,
+        temporal,
+        matting,
+        refiner,
+        compositor: Compositor::new(background).with_curve(curve).with_sampling(sampling),
+    };
+
+    let mut failures = 0;
+    for batch in images.chunks(batch_size) {
+        let t = Instant::now();
+        let results = process_files(&body_pix, batch, &mut output);
+        // Images in a batch are segmented together, so each gets its share of the time
+        let ms = t.elapsed().as_micros() as f32 / 1000. / batch.len() as f32;
+        for ((image_path, _), result) in batch.iter().zip(results) {
+            match result {
+                Ok(()) => println!("{}: {} ms", image_path.display(), ms),
+                Err(err) => {
+                    eprintln!("{}: {}", image_path.display(), err);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    println!("Processed {} of {} files", images.len() - failures, images.len());
+    if failures > 0 {
+        exit(1);
+    }
+}
+
+// Walks a directory, remembering where each image is relative to the input
+// so the output can mirror the directory structure
+fn collect_images(dir: &Path, relative_dir: &Path, patterns: &[Pattern], images: &mut Vec<(PathBuf, PathBuf)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Could not read directory {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() {
+            collect_images(&path, &relative_dir.join(&file_name), patterns, images);
+        } else if patterns.iter().any(|pattern| pattern.matches(&file_name)) {
+            images.push((path, relative_dir.to_path_buf()));
+        }
+    }
+}
+
+struct Output<'a> {
+    dir: PathBuf,
+    effects: Vec<&'a str>,
+    matte_format: &'a str,
+    curve: AlphaCurve,
+    sampling: Sampling,
+    threshold: f32,
+    temporal: Option<TemporalFilter>, // For videos
+    matting: Option<Matting>,
+    refiner: Option<MaskRefiner>,
+    compositor: Compositor,
+}
+
+#[cfg(feature = "tract")]
+fn load_onnx(path: &Path, spec: &ModelSpec) -> Result<BodyPix, Box<dyn std::error::Error>> {
+    Ok(BodyPix::from_onnx(path, spec.model_type)?)
+}
+
+#[cfg(not(feature = "tract"))]
+fn load_onnx(_path: &Path, _spec: &ModelSpec) -> Result<BodyPix, Box<dyn std::error::Error>> {
+    Err("ONNX models need the \"tract\" feature".into())
+}
+
+#[cfg(feature = "tensorflow")]
+fn load_spec(
+    models_dir: &Path,
+    spec: &ModelSpec,
+    options: &BodyPixOptions,
+) -> Result<BodyPix, Box<dyn std::error::Error>> {
+    Ok(BodyPix::from_spec_with_options(models_dir, spec, options)?)
+}
+
+#[cfg(not(feature = "tensorflow"))]
+fn load_spec(
+    _models_dir: &Path,
+    _spec: &ModelSpec,
+    _options: &BodyPixOptions,
+) -> Result<BodyPix, Box<dyn std::error::Error>> {
+    Err("frozen graph models need the \"tensorflow\" feature, or pass an ONNX model with --onnx".into())
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().unwrap_or_else(|err| {
+        eprintln!("Invalid {} \"{}\": {}", name, value, err);
+        exit(2);
+    })
+}
+
+// Samples the mask at the size of the image, once for all the effects. Video
+// frames are smoothed with the frames before them.
+fn full_mask(mask: &Segments, image: &DynamicImage, output: &mut Output, is_video: bool) -> bodypix::Result<Mask> {
+    let mask = match &output.matting {
+        Some(matting) => matting.matte(mask, image, output.sampling)?,
+        None => mask.upsample(output.sampling, Some(image)),
+    };
+    let mask = match &mut output.temporal {
+        Some(temporal) if is_video => temporal.filter(&mask, image),
+        _ => mask,
+    };
+    Ok(match &output.refiner {
+        Some(refiner) => refiner.refine(&mask),
+        None => mask,
+    })
+}
+
+fn parse_background(value: &str) -> Result<Background, Box<dyn std::error::Error>> {
+    if let Some(hex) = value.strip_prefix('#') {
+        let colour = u32::from_str_radix(hex, 16)?;
+        if hex.len() != 6 {
+            return Err(format!("expected a colour like #00ff00, got \"{}\"", value).into());
+        }
+        let [_, red, green, blue] = colour.to_be_bytes();
+        Ok(Background::Color(bodypix::image::Rgb([red, green, blue])))
+    } else if value == "blur" {
+        Ok(Background::Blur(10.))
+    } else if let Some(sigma) = value.strip_prefix("blur:") {
+        Ok(Background::Blur(sigma.parse()?))
+    } else {
+        Ok(Background::Image(ImageReader::open(value)?.decode()?))
+    }
+}
+
+fn apply_effect(effect: &str, mask: &Mask, image: &DynamicImage, output: &mut Output) -> DynamicImage {
+    match effect {
+        "mask" => mask_to_image(mask, output.sampling),
+        "silhouette" => create_silhouette(mask, image, output.threshold, output.sampling),
+        "cutout" => create_cutout(mask, image, output.threshold, output.sampling),
+        "background" => output.compositor.composite(mask, image),
+        "matte" => create_alpha_matte(mask, image, output.curve, output.sampling),
+        _ => unreachable!(),
+    }
+}
+
+// Segments the images among the files in one run, and the videos one by one.
+// Gives a result for each file, in order.
+fn process_files(
+    body_pix: &BodyPix,
+    files: &[(PathBuf, PathBuf)],
+    output: &mut Output,
+) -> Vec<Result<(), Box<dyn std::error::Error>>> {
+    let mut results: Vec<Option<Result<(), Box<dyn std::error::Error>>>> = files.iter().map(|_| None).collect();
+    let mut indices = Vec::new();
+    let mut images = Vec::new();
+
+    for (index, (path, relative_dir)) in files.iter().enumerate() {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+            let result = match output_dir(output, relative_dir) {
+                Ok(dir) => process_video_file(body_pix, path, &extension, &dir, output),
+                Err(err) => Err(err),
+            };
+            results[index] = Some(result);
+            continue;
+        }
+
+        match decode(path) {
+            Ok(image) => {
+                indices.push(index);
+                images.push(image);
+            }
+            Err(err) => results[index] = Some(Err(err)),
+        }
+    }
+
+    match body_pix.process_batch(&images) {
+        Ok(masks) => {
+            for ((index, image), mask) in indices.into_iter().zip(images.iter()).zip(masks.iter()) {
+                let (path, relative_dir) = &files[index];
+                results[index] = Some(save_effects(path, relative_dir, mask, image, output));
+            }
+        }
+        Err(err) => {
+            for index in indices {
+                results[index] = Some(Err(err.to_string().into()));
+            }
+        }
+    }
+
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+fn decode(path: &Path) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    Ok(ImageReader::open(path)?.decode()?)
+}
+
+fn output_dir(output: &Output, relative_dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let output_dir = output.dir.join(relative_dir);
+    fs::create_dir_all(&output_dir)?;
+    Ok(output_dir)
+}
+
+fn save_effects(
+    path: &Path,
+    relative_dir: &Path,
+    mask: &Segments,
+    image: &DynamicImage,
+    output: &mut Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_dir = output_dir(output, relative_dir)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mask = full_mask(mask, image, output, false)?;
+
+    for effect in output.effects.clone() {
+        let output_image = apply_effect(effect, &mask, image, output);
+        if effect == "matte" {
+            let file_name = format!("{}_{}.{}", stem, effect, output.matte_format);
+            save_with_alpha(&output_image, &output_dir.join(file_name))?;
+        } else {
+            output_image.save(output_dir.join(format!("{}_{}.png", stem, effect)))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Writes one video per effect, in the same container as the input. Mattes
+// go to a luma-only .y4m track for .y4m input, and to .webm with alpha otherwise.
+fn process_video_file(
+    body_pix: &BodyPix,
+    path: &Path,
+    extension: &str,
+    output_dir: &Path,
+    output: &mut Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut source = open_video(path)?;
+    let frame_rate = source.frame_rate().unwrap_or((30, 1));
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut sinks: Vec<Box<dyn VideoSink>> = Vec::new();
+    for effect in &output.effects {
+        let is_matte = *effect == "matte";
+        let sink_extension = if is_matte && extension != "y4m" {
+            "webm"
+        } else {
+            extension
+        };
+        let file_name = format!("{}_{}.{}", stem, effect, sink_extension);
+        sinks.push(create_video(&output_dir.join(file_name), frame_rate, is_matte)?);
+    }
+
+    let effects = output.effects.clone();
+    if let Some(temporal) = &mut output.temporal {
+        temporal.reset();
+    }
+    let frames = for_each_segmented_frame(&mut source, body_pix, |frame, mask| {
+        let mask = full_mask(mask, frame, output, true)?;
+        for (effect, sink) in effects.iter().zip(sinks.iter_mut()) {
+            sink.write_frame(&apply_effect(effect, &mask, frame, output))?;
+        }
+        Ok(())
+    })?;
+
+    for sink in sinks.iter_mut() {
+        sink.finish()?;
+    }
+    println!("{}: {} frames", path.display(), frames);
+
+    Ok(())
+}
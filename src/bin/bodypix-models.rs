@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+use bodypix::{tfjs, ModelSpec, MODELS};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// Only used when asked to, the build never downloads anything
+const DEFAULT_SOURCE: &str = "https://storage.googleapis.com/tfjs-models/savedmodel";
+
+fn main() {
+    let model_names: Vec<String> = MODELS.iter().map(ModelSpec::name).collect();
+    let model_name_refs: Vec<&str> = model_names.iter().map(String::as_str).collect();
+
+    let models_dir = Arg::with_name("models-dir")
+        .help("Directory for the converted models")
+        .long("models-dir")
+        .takes_value(true)
+        .default_value("assets/models");
+    let lockfile = Arg::with_name("lockfile")
+        .help("SHA-256 checksums of the model files, in sha256sum format")
+        .long("lockfile")
+        .takes_value(true)
+        .default_value("models.lock");
+
+    let matches = App::new("bodypix-models")
+        .about("Installs the BodyPix models from a mirror, or from the TensorFlow.js model storage")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Converts models into the models directory, skipping those that are up to date")
+                .arg(
+                    Arg::with_name("model")
+                        .help("Models to install [default: all]")
+                        .multiple(true)
+                        .possible_values(&model_name_refs),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("A directory or tarball laid out like the model storage, or the URL of one")
+                        .short("s")
+                        .long("source")
+                        .takes_value(true)
+                        .default_value(DEFAULT_SOURCE),
+                )
+                .arg(models_dir.clone())
+                .arg(lockfile.clone())
+                .arg(
+                    Arg::with_name("update-lock")
+                        .help("Record the checksums of the installed files, instead of requiring them to match")
+                        .long("update-lock"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the installed models against the lockfile")
+                .arg(models_dir)
+                .arg(lockfile)
+                .arg(
+                    Arg::with_name("allow-missing")
+                        .help("Only check the models that are installed, instead of failing on the others")
+                        .long("allow-missing"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("list").about("Lists the models that can be installed"))
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("install", Some(args)) => install(args),
+        ("verify", Some(args)) => verify(args),
+        ("list", _) => {
+            for spec in MODELS.iter() {
+                println!("{:24} {:28} {}", spec.name(), spec.label(), spec.file_name());
+            }
+            Ok(())
+        }
+        _ => unreachable!(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn install(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let models_dir = Path::new(args.value_of("models-dir").unwrap());
+    let update_lock = args.is_present("update-lock");
+    let mut lockfile = Lockfile::read(Path::new(args.value_of("lockfile").unwrap()))?;
+    let specs: Vec<&ModelSpec> = match args.values_of("model") {
+        Some(names) => names.map(|name| ModelSpec::find(name).unwrap()).collect(),
+        None => MODELS.iter().collect(),
+    };
+    fs::create_dir_all(models_dir)?;
+
+    // Opened on first use, so nothing is fetched or unpacked when all is up to date
+    let mut source = None;
+    for spec in specs {
+        let output = models_dir.join(spec.file_name());
+        if lockfile.matches(&spec.file_name(), &output)? {
+            println!("{}: up to date", spec.name());
+            continue;
+        }
+
+        if source.is_none() {
+            source = Some(Source::open(args.value_of("source").unwrap())?);
+        }
+        install_model(spec, source.as_ref().unwrap(), &output, &mut lockfile, update_lock)?;
+        println!("{}: installed {}", spec.name(), output.display());
+    }
+
+    if lockfile.changed {
+        lockfile.write()?;
+        println!("Updated {}", lockfile.path.display());
+    }
+    Ok(())
+}
+
+fn install_model(
+    spec: &ModelSpec,
+    source: &Source,
+    output: &Path,
+    lockfile: &mut Lockfile,
+    update_lock: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let model_json = format!("{}/{}", spec.url_path(), spec.model_json());
+    let json_path = source.file(&model_json)?;
+    lockfile.check(&model_json, &sha256(&json_path)?, update_lock)?;
+
+    for shard in shard_paths(&json_path)? {
+        let shard = format!("{}/{}", spec.url_path(), shard);
+        let shard_path = source.file(&shard)?;
+        lockfile.check(&shard, &sha256(&shard_path)?, update_lock)?;
+    }
+
+    // Converted next to the output and moved into place, so a failed run
+    // doesn't leave a model that looks installed
+    let partial = output.with_extension("pb.partial");
+    tfjs::convert_to_file(&json_path, &partial)?;
+    if let Err(err) = lockfile.check(&spec.file_name(), &sha256(&partial)?, update_lock) {
+        fs::remove_file(&partial)?;
+        return Err(err);
+    }
+    fs::rename(&partial, output)?;
+
+    Ok(())
+}
+
+fn verify(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let models_dir = Path::new(args.value_of("models-dir").unwrap());
+    let lockfile = Lockfile::read(Path::new(args.value_of("lockfile").unwrap()))?;
+    let allow_missing = args.is_present("allow-missing");
+
+    let mut failures = 0;
+    for spec in MODELS.iter() {
+        let output = models_dir.join(spec.file_name());
+        if !output.exists() {
+            println!("{}: not installed", spec.name());
+            if !allow_missing {
+                failures += 1;
+            }
+        } else if lockfile.matches(&spec.file_name(), &output)? {
+            println!("{}: ok", spec.name());
+        } else {
+            println!("{}: does not match {}", spec.name(), lockfile.path.display());
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} models failed verification", failures).into());
+    }
+    Ok(())
+}
+
+fn shard_paths(model_json: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let model: Value = serde_json::from_slice(&fs::read(model_json)?)?;
+    let groups = model["weightsManifest"].as_array().map(Vec::as_slice).unwrap_or_default();
+
+    Ok(groups
+        .iter()
+        .filter_map(|group| group["paths"].as_array())
+        .flatten()
+        .filter_map(|path| path.as_str().map(str::to_string))
+        .collect())
+}
+
+fn sha256(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{:x}", Sha256::digest(&fs::read(path)?)))
+}
+
+// Checksums of the source files, by their path in the model storage, and of
+// the converted models, by file name. Written in the format of sha256sum.
+struct Lockfile {
+    path: PathBuf,
+    checksums: BTreeMap<String, String>,
+    changed: bool,
+}
+
+impl Lockfile {
+    fn read(path: &Path) -> Result<Lockfile, Box<dyn std::error::Error>> {
+        let mut checksums = BTreeMap::new();
+        if path.exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some(checksum), Some(name)) => checksums.insert(name.to_string(), checksum.to_string()),
+                    _ => return Err(format!("Invalid line in {}: {}", path.display(), line).into()),
+                };
+            }
+        }
+
+        Ok(Lockfile {
+            path: path.to_path_buf(),
+            checksums,
+            changed: false,
+        })
+    }
+
+    // Whether a file exists and has the checksum in the lockfile
+    fn matches(&self, name: &str, path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.checksums.get(name) {
+            Some(expected) if path.exists() => Ok(*expected == sha256(path)?),
+            _ => Ok(false),
+        }
+    }
+
+    fn check(&mut self, name: &str, checksum: &str, update: bool) -> Result<(), Box<dyn std::error::Error>> {
+        match self.checksums.get(name) {
+            Some(expected) if expected == checksum => Ok(()),
+            _ if update => {
+                self.checksums.insert(name.to_string(), checksum.to_string());
+                self.changed = true;
+                Ok(())
+            }
+            Some(expected) => Err(format!(
+                "{} has checksum {}, but {} expects {}",
+                name,
+                checksum,
+                self.path.display(),
+                expected
+            )
+            .into()),
+            None => Err(format!(
+                "{} is not in {}, install with --update-lock to add it",
+                name,
+                self.path.display()
+            )
+            .into()),
+        }
+    }
+
+    fn write(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut content = String::from("# Written by bodypix-models install --update-lock\n");
+        for (name, checksum) in &self.checksums {
+            content.push_str(&format!("{}  {}\n", checksum, name));
+        }
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+// Where the model files come from. Tarballs are unpacked and URLs fetched
+// into a staging directory, so they are read just like a local mirror.
+struct Source {
+    location: String,
+    url: Option<String>,
+    root: PathBuf,
+    staging: Option<PathBuf>,
+}
+
+impl Source {
+    fn open(location: &str) -> Result<Source, Box<dyn std::error::Error>> {
+        let staging = env::temp_dir().join(format!("bodypix-models-{}", std::process::id()));
+
+        if location.starts_with("http://") || location.starts_with("https://") {
+            fs::create_dir_all(&staging)?;
+            Ok(Source {
+                location: location.to_string(),
+                url: Some(location.trim_end_matches('/').to_string()),
+                root: staging.clone(),
+                staging: Some(staging),
+            })
+        } else if Path::new(location).is_dir() {
+            Ok(Source {
+                location: location.to_string(),
+                url: None,
+                root: PathBuf::from(location),
+                staging: None,
+            })
+        } else {
+            fs::create_dir_all(&staging)?;
+            let mut source = Source {
+                location: location.to_string(),
+                url: None,
+                root: staging.clone(),
+                staging: Some(staging),
+            };
+            unpack(Path::new(location), &source.root)?;
+            source.root = mirror_root(&source.root);
+            Ok(source)
+        }
+    }
+
+    // Path to a file of the mirror, fetching it first for URL sources
+    fn file(&self, relative: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = self.root.join(relative);
+        if let Some(url) = &self.url {
+            if !path.exists() {
+                fs::create_dir_all(path.parent().unwrap())?;
+                fetch(&format!("{}/{}", url, relative), &path)?;
+            }
+        }
+
+        if !path.is_file() {
+            return Err(format!("{} not found in {}", relative, self.location).into());
+        }
+        Ok(path)
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        if let Some(staging) = &self.staging {
+            let _ = fs::remove_dir_all(staging);
+        }
+    }
+}
+
+// Through curl, like the videos go through ffmpeg, rather than pulling an HTTP stack into the crate
+fn fetch(url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let status = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--output"])
+        .arg(path)
+        .arg(url)
+        .status()
+        .map_err(|err| format!("Could not run curl: {}", err))?;
+
+    if !status.success() {
+        let _ = fs::remove_file(path);
+        return Err(format!("Downloading {} failed ({})", url, status).into());
+    }
+    Ok(())
+}
+
+fn unpack(tarball: &Path, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = fs::File::open(tarball).map_err(|err| format!("Could not open {}: {}", tarball.display(), err))?;
+    let name = tarball.to_string_lossy();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        tar::Archive::new(GzDecoder::new(file)).unpack(dir)?;
+    } else {
+        tar::Archive::new(file).unpack(dir)?;
+    }
+    Ok(())
+}
+
+// Tarballs often wrap everything in one top directory, so look for "bodypix" one level down too
+fn mirror_root(dir: &Path) -> PathBuf {
+    if dir.join("bodypix").is_dir() {
+        return dir.to_path_buf();
+    }
+
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.join("bodypix").is_dir())
+        .unwrap_or_else(|| dir.to_path_buf())
+}
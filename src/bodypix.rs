@@ -1,249 +1,509 @@
-use std::fs::File;
-use std::io::Read;
-use std::ops::Index;
+#[cfg(any(feature = "tensorflow", feature = "tract"))]
+use std::path::Path;
+#[cfg(feature = "tensorflow")]
 use std::path::PathBuf;
 
-use tensorflow::{Graph, ImportGraphDefOptions, Session, SessionOptions, SessionRunArgs, Tensor};
-
-use nannou::image;
-use nannou::image::{DynamicImage, GenericImageView, Pixel};
-use std::time::Instant;
-
-#[derive(Clone, Copy)]
-pub enum ModelType {
-    MobileNet,
-    ResNet,
-}
+#[cfg(feature = "tensorflow")]
+use tensorflow::Tensor;
+
+use image::DynamicImage;
+
+#[cfg(feature = "tensorflow")]
+use crate::backend::TensorflowBackend;
+#[cfg(feature = "tract")]
+use crate::backend::TractBackend;
+use crate::backend::{OutputTensor, SegmentationBackend};
+use crate::error::{BodyPixError, Result};
+use crate::models::{ModelSpec, MODELS};
+pub use crate::models::ModelType;
+use crate::multi_person::{segment_people, MultiPersonConfig, PersonSegmentation};
+use crate::operations::{self, GraphOperations};
+#[cfg(any(feature = "tensorflow", feature = "tract"))]
+use crate::options::BodyPixOptions;
+use crate::parts::PartSegments;
+use crate::pose::{decode_multiple_poses, decode_single_pose, Grid, KeypointType, MultiPoseConfig, Pose, PoseOutputs};
+use crate::preprocessing::{
+    default_normalization, fill_input, input_dims, input_size, InputConfig, InputTransform, Normalization,
+};
+use crate::sampling::{self, Mask, Sampling};
+#[cfg(feature = "tensorflow")]
+use crate::tfjs;
 
 pub struct BodyPix {
-    graph: Graph,
-    session: Session,
+    backend: Box<dyn SegmentationBackend>,
     stride: u32,
     model_type: ModelType,
-    // input_operations
-    // output_operations
+    operations: GraphOperations,
+    input_config: InputConfig,
+    normalization: Box<dyn Normalization>,
 }
 
 pub struct Segments {
     pub orig_width: usize,
     pub orig_height: usize,
-    width: usize,
-    height: usize,
-    stride: u32,
-    values: Vec<f32>,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) stride: u32,
+    pub(crate) values: Vec<f32>,
+    pub(crate) transform: InputTransform, // From the original image to the network input
 }
 
 impl Segments {
-    fn sigmoid(val: f32) -> f32 {
+    pub(crate) fn sigmoid(val: f32) -> f32 {
         let neg_val = -val;
         let denominator = 1. + neg_val.exp();
         1. / denominator
     }
 
-    pub fn from_tensor(stride: u32, orig_width: usize, orig_height: usize, t: &Tensor<f32>) -> Segments {
-        let height = t.shape().index(1).unwrap() as usize;
-        let width = t.shape().index(2).unwrap() as usize;
+    #[cfg(feature = "tensorflow")]
+    pub fn from_tensor(stride: u32, orig_width: usize, orig_height: usize, t: &Tensor<f32>) -> Result<Segments> {
+        Segments::from_output(stride, orig_width, orig_height, &OutputTensor::from(t))
+    }
 
-        let mut values = vec![0f32; width * height];
+    pub fn from_output(stride: u32, orig_width: usize, orig_height: usize, t: &OutputTensor) -> Result<Segments> {
+        Segments::from_tensor_channel(stride, InputTransform::identity(orig_width, orig_height), t, 0)
+    }
 
-        // Is there a faster way to copy all the elements, and treat them? Or should we just move the sigmoid function to a shader and make one single copy?
-        for x in 0..width {
-            for y in 0..height {
-                let seg_val = t.get(&[0, y as u64, x as u64, 0]);
-                values[x + y * width] = Segments::sigmoid(seg_val);
-            }
-        }
-        let values = values;
+    // Reads one channel of a [1, height, width, channels] output tensor
+    pub(crate) fn from_tensor_channel(
+        stride: u32,
+        transform: InputTransform,
+        t: &OutputTensor,
+        channel: usize,
+    ) -> Result<Segments> {
+        Segments::from_tensor_item(stride, transform, t, 0, channel)
+    }
 
-        Segments {
-            orig_width,
-            orig_height,
+    // Reads one channel of one image in a [batch, height, width, channels] output tensor
+    pub(crate) fn from_tensor_item(
+        stride: u32,
+        transform: InputTransform,
+        t: &OutputTensor,
+        item: usize,
+        channel: usize,
+    ) -> Result<Segments> {
+        if t.dims().len() != 4 || t.dims()[0] as usize <= item || t.dims()[3] as usize <= channel {
+            return Err(BodyPixError::InvalidTensor(format!(
+                "expected item {}, channel {} in tensor of rank 4, got shape {:?}",
+                item,
+                channel,
+                t.dims()
+            )));
+        }
+        let height = t.dims()[1] as usize;
+        let width = t.dims()[2] as usize;
+        let channels = t.dims()[3] as usize;
+
+        // The tensor is row-major, like the values, so this reads it in order
+        let item_len = height * width * channels;
+        let values = t[item * item_len..(item + 1) * item_len]
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .map(|&seg_val| Segments::sigmoid(seg_val))
+            .collect();
+
+        Ok(Segments {
+            orig_width: transform.orig_width,
+            orig_height: transform.orig_height,
             width,
             height,
             stride,
             values,
-        }
+            transform,
+        })
     }
 
-    pub fn no_interpolation(self: &Segments, x: usize, y: usize) -> f32 {
-        let step_x = 1 + x / self.stride as usize;
-        let step_y = 1 + y / self.stride as usize;
-        self.values[step_x + step_y * self.width]
+    // Position of a pixel of the original image in the output grid
+    pub(crate) fn grid_coords(&self, x: usize, y: usize) -> (f32, f32) {
+        let (input_x, input_y) = self.transform.to_input(x as f32, y as f32);
+        let stride = self.stride as f32;
+        (input_x.max(0.) / stride, input_y.max(0.) / stride)
     }
 
-    pub fn linear_mean(self: &Segments, x: usize, y: usize) -> f32 {
-        let step_x = x / self.stride as usize;
-        let step_y = y / self.stride as usize;
-
-        let x1: usize = step_x;
-        let x2: usize = if x1 >= self.width - 1 {
-            x1
-        } else {
-            1 + step_x
-        };
-
-        let y1 = step_y;
-        let y2 = if y1 >= self.height - 1 {
-            y1
-        } else {
-            1 + step_y
-        };
-
-        let x_remainder = x as u32 % self.stride;
-        let y_remainder = y as u32 % self.stride;
-
-        let part_x1 = (self.stride - x_remainder) as f32 / self.stride as f32;
-        let part_x2 = 1. - part_x1;
-        let part_y1 = (self.stride - y_remainder) as f32 / self.stride as f32;
-        let part_y2 = 1. - part_y1;
-
-        let mean_x1 = self.values[x1 + y1 * self.width] * part_y1 + self.values[x1 + y2 * self.width] * part_y2;
-        let mean_x2 = self.values[x2 + y1 * self.width] * part_y1 + self.values[x2 + y2 * self.width] * part_y2;
+    // Foreground probability at a pixel of the original image
+    pub fn sample(&self, sampling: Sampling, x: usize, y: usize) -> f32 {
+        let (grid_x, grid_y) = self.grid_coords(x, y);
+        match sampling {
+            Sampling::Nearest => sampling::nearest(self, grid_x, grid_y),
+            Sampling::Bicubic => sampling::bicubic(self, grid_x, grid_y),
+            // Needs the original image, which upsample takes
+            Sampling::Bilinear | Sampling::JointBilateral { .. } => sampling::bilinear(self, grid_x, grid_y),
+        }
+    }
 
-        let mean = mean_x1 * part_x1 + mean_x2 * part_x2;
+    pub fn no_interpolation(&self, x: usize, y: usize) -> f32 {
+        self.sample(Sampling::Nearest, x, y)
+    }
 
-        mean
+    pub fn linear_mean(&self, x: usize, y: usize) -> f32 {
+        self.sample(Sampling::Bilinear, x, y)
     }
 
+    // Samples every pixel of the original image once. The guide is the
+    // original image, for joint bilateral sampling.
+    pub fn upsample(&self, sampling: Sampling, guide: Option<&DynamicImage>) -> Mask {
+        sampling::upsample(self, sampling, guide)
+    }
 }
 
 impl BodyPix {
-    pub fn models() -> (
-        Vec<&'static str>,
-        Vec<&'static str>,
-        Vec<u32>,
-        Vec<ModelType>,
-    ) {
-        (
-            vec![
-                "MobileNet 0.50 stride 8",
-                "MobileNet 0.50 stride 16",
-                "MobileNet 0.75 stride 8",
-                "MobileNet 0.75 stride 16",
-                "MobileNet 1.00 stride 8",
-                "MobileNet 1.00 stride 16",
-                "ResNet 0.50 stride 16",
-                "ResNet 0.50 stride 32",
-            ],
-            vec![
-                "bodypix_mobilenet_float_050-stride8.pb",
-                "bodypix_mobilenet_float_050-stride8.pb",
-                "bodypix_mobilenet_float_075-stride8.pb",
-                "bodypix_mobilenet_float_075-stride8.pb",
-                "bodypix_mobilenet_float_100-stride8.pb",
-                "bodypix_mobilenet_float_100-stride8.pb",
-                "bodypix_resnet50_float-stride16.pb",
-                "bodypix_resnet50_float-stride32.pb",
-            ],
-            vec![8, 16, 8, 16, 8, 16, 16, 32],
-            vec![
-                ModelType::MobileNet,
-                ModelType::MobileNet,
-                ModelType::MobileNet,
-                ModelType::MobileNet,
-                ModelType::MobileNet,
-                ModelType::MobileNet,
-                ModelType::ResNet,
-                ModelType::ResNet,
-            ],
-        )
+    pub fn models() -> &'static [ModelSpec] {
+        &MODELS
     }
 
-    pub fn from_model(model_path: PathBuf, stride: u32, model_type: ModelType) -> BodyPix {
-        let mut model_data: Vec<u8> = Vec::new();
-        let mut file = File::open(model_path).unwrap();
-        file.read_to_end(&mut model_data).unwrap();
+    // Runs on any backend, finding the operations, model type and stride in the model
+    pub fn from_backend(backend: Box<dyn SegmentationBackend>) -> Result<BodyPix> {
+        let model_type = operations::detect_model_type(backend.as_ref());
+        let operations = GraphOperations::resolve(backend.as_ref(), model_type, None)?;
 
-        let mut graph = Graph::new();
-        graph
-            .import_graph_def_with_results(&model_data, &ImportGraphDefOptions::new())
-            .unwrap();
+        let mut body_pix = BodyPix::new(backend, 0, model_type, operations); // Stride measured below
+        body_pix.stride = body_pix.measure_stride()?;
 
-        let session = Session::new(&SessionOptions::new(), &graph).unwrap();
+        Ok(body_pix)
+    }
 
+    fn new(
+        backend: Box<dyn SegmentationBackend>,
+        stride: u32,
+        model_type: ModelType,
+        operations: GraphOperations,
+    ) -> BodyPix {
         BodyPix {
+            backend,
             stride,
-            graph,
-            session,
             model_type,
+            operations,
+            input_config: InputConfig::default(),
+            normalization: default_normalization(model_type),
         }
     }
 
-    pub fn process_image(&self, image: &DynamicImage) -> Segments {
-        let orig_width = image.width();
-        let orig_height = image.height();
+    // Loads a model from the registry, checking that it has the stride it claims
+    #[cfg(feature = "tensorflow")]
+    pub fn from_spec(models_dir: &Path, spec: &ModelSpec) -> Result<BodyPix> {
+        BodyPix::from_spec_with_options(models_dir, spec, &BodyPixOptions::default())
+    }
 
-        // Nudge target width/height one step above stride (improves quality!)
-        let target_width = orig_width + 1;
-        let target_height = orig_height + 1;
+    #[cfg(feature = "tensorflow")]
+    pub fn from_spec_with_options(models_dir: &Path, spec: &ModelSpec, options: &BodyPixOptions) -> Result<BodyPix> {
+        let mut body_pix = BodyPix::from_model_with_options(
+            models_dir.join(spec.file_name()),
+            spec.stride,
+            spec.model_type,
+            options,
+        )?;
+        if let Some(overrides) = &spec.operations {
+            body_pix.operations = GraphOperations::resolve(body_pix.backend.as_ref(), spec.model_type, Some(overrides))?;
+        }
 
-        let max_x = orig_width - 1;
-        let max_y = orig_height - 1;
+        let stride = body_pix.measure_stride()?;
+        if stride != spec.stride {
+            return Err(BodyPixError::ModelMismatch(format!(
+                "{} should have stride {}, but has stride {}",
+                spec.file_name(),
+                spec.stride,
+                stride
+            )));
+        }
 
-        // Setup tensor args
-        let vec_size = target_width * target_height * 3;
-        let mut flattened: Vec<f32> = Vec::with_capacity(vec_size as usize); // TODO: Maybe have a reusable buffer, instead of re-allocating every frame?
+        Ok(body_pix)
+    }
 
-        match self.model_type {
-            ModelType::MobileNet => {
-                for y in 0..target_height {
-                    for x in 0..target_width {
-                        let pixel = image.get_pixel(std::cmp::min(x, max_x), std::cmp::min(y, max_y)).to_rgb();
+    #[cfg(feature = "tensorflow")]
+    pub fn from_model(model_path: PathBuf, stride: u32, model_type: ModelType) -> Result<BodyPix> {
+        BodyPix::from_model_with_options(model_path, stride, model_type, &BodyPixOptions::default())
+    }
 
-                        let red = pixel.0[0] as f32 / 127.5 - 1.;
-                        let green = pixel.0[1] as f32 / 127.5 - 1.;
-                        let blue = pixel.0[2] as f32 / 127.5 - 1.;
+    #[cfg(feature = "tensorflow")]
+    pub fn from_model_with_options(
+        model_path: PathBuf,
+        stride: u32,
+        model_type: ModelType,
+        options: &BodyPixOptions,
+    ) -> Result<BodyPix> {
+        let backend = TensorflowBackend::load(&model_path, options)?;
+        BodyPix::with_backend(Box::new(backend), stride, model_type)
+    }
 
-                        flattened.push(red);
-                        flattened.push(green);
-                        flattened.push(blue);
-                    }
-                }
-            }
-            ModelType::ResNet => {
-                for y in 0..target_height {
-                    for x in 0..target_width {
-                        let pixel = image.get_pixel(std::cmp::min(x, max_x), std::cmp::min(y, max_y)).to_rgb();
-
-                        let red = pixel.0[0] as f32 - 123.15;
-                        let green = pixel.0[1] as f32 - 115.9;
-                        let blue = pixel.0[2] as f32 - 103.06;
-
-                        flattened.push(red);
-                        flattened.push(green);
-                        flattened.push(blue);
-                    }
-                }
-            }
+    // Converts a TensorFlow.js graph model on the fly, so no frozen graph is needed
+    #[cfg(feature = "tensorflow")]
+    pub fn from_tfjs(model_json: &Path, model_type: ModelType) -> Result<BodyPix> {
+        BodyPix::from_tfjs_with_options(model_json, model_type, &BodyPixOptions::default())
+    }
+
+    #[cfg(feature = "tensorflow")]
+    pub fn from_tfjs_with_options(
+        model_json: &Path,
+        model_type: ModelType,
+        options: &BodyPixOptions,
+    ) -> Result<BodyPix> {
+        let graph_def = tfjs::convert(model_json)?;
+        let backend = TensorflowBackend::from_graph_def(&graph_def, options)?;
+
+        let mut body_pix = BodyPix::with_backend(Box::new(backend), 0, model_type)?; // Stride measured below
+        body_pix.stride = body_pix.measure_stride()?;
+
+        Ok(body_pix)
+    }
+
+    // Loads a SavedModel directory, feeding and fetching through the tensors
+    // named by one of its signatures. The model type and stride are worked out
+    // from the graph, as a SavedModel doesn't say.
+    #[cfg(feature = "tensorflow")]
+    pub fn from_saved_model(dir: &Path, tag: &str, signature: &str) -> Result<BodyPix> {
+        BodyPix::from_saved_model_with_options(dir, tag, signature, &BodyPixOptions::default())
+    }
+
+    #[cfg(feature = "tensorflow")]
+    pub fn from_saved_model_with_options(
+        dir: &Path,
+        tag: &str,
+        signature: &str,
+        options: &BodyPixOptions,
+    ) -> Result<BodyPix> {
+        let (backend, operations) = TensorflowBackend::from_saved_model(dir, tag, signature, options)?;
+        let model_type = operations::detect_model_type(&backend);
+
+        let mut body_pix = BodyPix::new(Box::new(backend), 0, model_type, operations); // Stride measured below
+        body_pix.stride = body_pix.measure_stride()?;
+
+        Ok(body_pix)
+    }
+
+    // Loads an ONNX export of a model, run by tract without any native libraries
+    #[cfg(feature = "tract")]
+    pub fn from_onnx(model_path: &Path, model_type: ModelType) -> Result<BodyPix> {
+        let backend = TractBackend::load(model_path, &BodyPixOptions::default())?;
+
+        let mut body_pix = BodyPix::with_backend(Box::new(backend), 0, model_type)?; // Stride measured below
+        body_pix.stride = body_pix.measure_stride()?;
+
+        Ok(body_pix)
+    }
+
+    // For a known model type and stride, looking up the operations by name
+    #[cfg(any(feature = "tensorflow", feature = "tract"))]
+    fn with_backend(backend: Box<dyn SegmentationBackend>, stride: u32, model_type: ModelType) -> Result<BodyPix> {
+        let operations = GraphOperations::resolve(backend.as_ref(), model_type, None)?;
+        Ok(BodyPix::new(backend, stride, model_type, operations))
+    }
+
+    // The operations found in the graph, for checking what a converted model provides
+    pub fn operations(&self) -> &GraphOperations {
+        &self.operations
+    }
+
+    pub fn model_type(&self) -> ModelType {
+        self.model_type
+    }
+
+    // How large an image is made before it goes into the network
+    pub fn with_input_config(mut self, input_config: InputConfig) -> BodyPix {
+        self.input_config = input_config;
+        self
+    }
+
+    pub fn set_input_config(&mut self, input_config: InputConfig) {
+        self.input_config = input_config;
+    }
+
+    pub fn input_config(&self) -> &InputConfig {
+        &self.input_config
+    }
+
+    // For models trained on differently normalized images than the model type's default
+    pub fn with_normalization(mut self, normalization: Box<dyn Normalization>) -> BodyPix {
+        self.normalization = normalization;
+        self
+    }
+
+    // Runs a small image through the model, to see which stride it really has
+    pub fn measure_stride(&self) -> Result<u32> {
+        // One pixel is added to the input, making it 257: a multiple of every stride, plus one
+        let probe = DynamicImage::new_rgb8(256, 256);
+        let (outputs, _) = self.run_with(&probe, &InputConfig::default(), &[self.operations.segments.as_str()])?;
+
+        let output_height = outputs[0].dims()[1] as u32;
+        if output_height < 2 {
+            return Err(BodyPixError::ModelMismatch(format!(
+                "unexpected segments shape {:?}",
+                outputs[0].dims()
+            )));
         }
+        Ok(256 / (output_height - 1))
+    }
 
-        assert_eq!(flattened.len(), vec_size as usize);
+    // Name of an output that the graph may not have
+    fn optional_output<'a>(&self, role: &str, name: &'a Option<String>, known: &[&str]) -> Result<&'a str> {
+        name.as_deref()
+            .ok_or_else(|| operations::not_found(&self.backend.operation_names(), role, known))
+    }
 
-        let input = Tensor::new(&[1, target_height as u64, target_width as u64, 3])
-            .with_values(&flattened)
-            .unwrap();
+    pub fn process_image(&self, image: &DynamicImage) -> Result<Segments> {
+        let (outputs, transform) = self.run(image, &[self.operations.segments.as_str()])?;
 
-        let mut args: SessionRunArgs = SessionRunArgs::new();
-        args.add_feed(
-            &self.graph.operation_by_name_required("sub_2").unwrap(),
-            0,
-            &input,
-        );
+        Segments::from_tensor_channel(self.stride, transform, &outputs[0], 0)
+    }
 
-        let segments_token = args.request_fetch(
-            &self
-                .graph
-                .operation_by_name_required("float_segments")
-                .unwrap(),
-            0,
-        );
+    // Segments several images in one session run, which keeps the CPU busier
+    // than running them one by one. Images of different sizes are padded to
+    // the largest, so batches of similarly sized images waste the least work.
+    pub fn process_batch(&self, images: &[DynamicImage]) -> Result<Vec<Segments>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        self.session.run(&mut args).unwrap();
+        let mut common_size = (0, 0);
+        for image in images {
+            let (width, height) = input_size(image, &self.input_config)?;
+            common_size = (common_size.0.max(width), common_size.1.max(height));
+        }
 
-        let segments_res: Tensor<f32> = args.fetch(segments_token).unwrap();
+        // Each image is written straight into its part of the batch tensor
+        let (width, height) = input_dims(common_size);
+        let mut transforms = Vec::with_capacity(images.len());
+        let outputs = self.backend.run(
+            &self.operations.input,
+            [images.len(), height, width, 3],
+            &mut |input| {
+                for (image, values) in images.iter().zip(input.chunks_mut(width * height * 3)) {
+                    transforms.push(fill_input(
+                        image,
+                        &self.input_config,
+                        self.normalization.as_ref(),
+                        common_size,
+                        values,
+                    )?);
+                }
+                Ok(())
+            },
+            &[self.operations.segments.as_str()],
+        )?;
+
+        transforms
+            .into_iter()
+            .enumerate()
+            .map(|(item, transform)| Segments::from_tensor_item(self.stride, transform, &outputs[0], item, 0))
+            .collect()
+    }
+
+    pub fn process_image_with_parts(&self, image: &DynamicImage) -> Result<(Segments, PartSegments)> {
+        let part_heatmaps =
+            self.optional_output("part heatmaps", &self.operations.part_heatmaps, &operations::PART_HEATMAPS_NAMES)?;
+        let (outputs, transform) = self.run(image, &[self.operations.segments.as_str(), part_heatmaps])?;
+
+        let segments = Segments::from_tensor_channel(self.stride, transform, &outputs[0], 0)?;
+        let parts = PartSegments::from_heatmaps(self.stride, transform, &outputs[1])?;
+
+        Ok((segments, parts))
+    }
+
+    pub fn estimate_single_pose(&self, image: &DynamicImage) -> Result<Pose> {
+        let (outputs, transform) = self.run(image, &self.pose_output_names()?)?;
+        let pose_outputs = self.pose_outputs(&transform, &outputs)?;
+
+        Ok(transform.pose_to_original(decode_single_pose(&pose_outputs)))
+    }
+
+    pub fn estimate_multiple_poses(&self, image: &DynamicImage, config: &MultiPoseConfig) -> Result<Vec<Pose>> {
+        let (outputs, transform) = self.run(image, &self.pose_output_names()?)?;
+        let pose_outputs = self.pose_outputs(&transform, &outputs)?;
+
+        Ok(decode_multiple_poses(&pose_outputs, config)
+            .into_iter()
+            .map(|pose| transform.pose_to_original(pose))
+            .collect())
+    }
 
-        let segments = Segments::from_tensor(self.stride, orig_width as usize, orig_height as usize, &segments_res);
+    // Segmentation and poses from a single inference pass
+    pub fn process_image_with_poses(
+        &self,
+        image: &DynamicImage,
+        config: &MultiPoseConfig,
+    ) -> Result<(Segments, Vec<Pose>)> {
+        let mut output_names = vec![self.operations.segments.as_str()];
+        output_names.extend_from_slice(&self.pose_output_names()?);
+        let (outputs, transform) = self.run(image, &output_names)?;
+
+        let segments = Segments::from_tensor_channel(self.stride, transform, &outputs[0], 0)?;
+        let pose_outputs = self.pose_outputs(&transform, &outputs[1..])?;
+        let poses = decode_multiple_poses(&pose_outputs, config)
+            .into_iter()
+            .map(|pose| transform.pose_to_original(pose))
+            .collect();
+
+        Ok((segments, poses))
+    }
+
+    // One mask per person, told apart by matching the long-range offsets to decoded poses
+    pub fn segment_multi_person(
+        &self,
+        image: &DynamicImage,
+        config: &MultiPersonConfig,
+    ) -> Result<Vec<PersonSegmentation>> {
+        let mut output_names = vec![
+            self.operations.segments.as_str(),
+            self.optional_output("long offsets", &self.operations.long_offsets, &operations::LONG_OFFSETS_NAMES)?,
+        ];
+        output_names.extend_from_slice(&self.pose_output_names()?);
+        let (outputs, transform) = self.run(image, &output_names)?;
+
+        let segments = Segments::from_tensor_channel(self.stride, transform, &outputs[0], 0)?;
+        let long_offsets = Grid::from_tensor(&outputs[1], 2 * KeypointType::COUNT)?;
+        let pose_outputs = self.pose_outputs(&transform, &outputs[2..])?;
+        let poses = decode_multiple_poses(&pose_outputs, &config.pose);
+
+        Ok(segment_people(&segments, poses, &long_offsets, config))
+    }
+
+    fn pose_output_names(&self) -> Result<[&str; 4]> {
+        let ops = &self.operations;
+        Ok([
+            self.optional_output("heatmaps", &ops.heatmaps, &operations::HEATMAPS_NAMES)?,
+            self.optional_output("short offsets", &ops.short_offsets, &operations::SHORT_OFFSETS_NAMES)?,
+            self.optional_output("displacement fwd", &ops.displacement_fwd, &operations::DISPLACEMENT_FWD_NAMES)?,
+            self.optional_output("displacement bwd", &ops.displacement_bwd, &operations::DISPLACEMENT_BWD_NAMES)?,
+        ])
+    }
+
+    fn pose_outputs(&self, transform: &InputTransform, outputs: &[OutputTensor]) -> Result<PoseOutputs> {
+        PoseOutputs::from_tensors(
+            self.stride,
+            transform,
+            &outputs[0],
+            &outputs[1],
+            &outputs[2],
+            &outputs[3],
+        )
+    }
+
+    // Runs the model on an image, and fetches the named outputs in the same order
+    fn run(&self, image: &DynamicImage, output_names: &[&str]) -> Result<(Vec<OutputTensor>, InputTransform)> {
+        self.run_with(image, &self.input_config, output_names)
+    }
 
-        segments
+    fn run_with(
+        &self,
+        image: &DynamicImage,
+        config: &InputConfig,
+        output_names: &[&str],
+    ) -> Result<(Vec<OutputTensor>, InputTransform)> {
+        let input_size = input_size(image, config)?;
+        let (width, height) = input_dims(input_size);
+
+        let mut transform = None;
+        let outputs = self.backend.run(
+            &self.operations.input,
+            [1, height, width, 3],
+            &mut |input| {
+                transform = Some(fill_input(image, config, self.normalization.as_ref(), input_size, input)?);
+                Ok(())
+            },
+            output_names,
+        )?;
+
+        let transform = transform.ok_or_else(|| BodyPixError::InvalidTensor("the input was never filled".to_string()))?;
+        Ok((outputs, transform))
     }
 }
@@ -0,0 +1,92 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, RgbImage};
+
+use crate::image_utils::AlphaCurve;
+use crate::sampling::{MaskSource, Sampling};
+
+pub enum Background {
+    // Scaled and cropped to cover the whole frame
+    Image(DynamicImage),
+    Color(image::Rgb<u8>),
+    // The original image, Gaussian blurred with this sigma
+    Blur(f32),
+}
+
+// Blends the people in an image onto a new background, using the soft mask.
+// Keeps the scaled background image around, so it is cheap to reuse on
+// consecutive frames of the same size.
+pub struct Compositor {
+    background: Background,
+    curve: AlphaCurve,
+    sampling: Sampling,
+    prepared_background: Option<RgbImage>,
+}
+
+impl Compositor {
+    pub fn new(background: Background) -> Compositor {
+        Compositor {
+            background,
+            curve: AlphaCurve::Linear,
+            sampling: Sampling::default(),
+            prepared_background: None,
+        }
+    }
+
+    pub fn with_curve(mut self, curve: AlphaCurve) -> Compositor {
+        self.curve = curve;
+        self
+    }
+
+    // How masks from the network are sampled at the size of the image
+    pub fn with_sampling(mut self, sampling: Sampling) -> Compositor {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+        self.prepared_background = None;
+    }
+
+    fn prepare_background(&mut self, orig: &DynamicImage) {
+        let (width, height) = orig.dimensions();
+        let is_stale = match &self.prepared_background {
+            Some(prepared) => prepared.dimensions() != (width, height),
+            None => true,
+        };
+
+        match &self.background {
+            Background::Image(img) if is_stale => {
+                let scaled = img.resize_to_fill(width, height, FilterType::Triangle);
+                self.prepared_background = Some(scaled.to_rgb8());
+            }
+            Background::Color(colour) if is_stale => {
+                self.prepared_background = Some(ImageBuffer::from_pixel(width, height, *colour));
+            }
+            Background::Blur(sigma) => {
+                self.prepared_background = Some(imageops::blur(&orig.to_rgb8(), *sigma));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn composite(&mut self, mask: &impl MaskSource, orig: &DynamicImage) -> DynamicImage {
+        self.prepare_background(orig);
+        let mask = mask.to_mask(self.sampling, Some(orig));
+        let background = self.prepared_background.as_ref().unwrap();
+        let curve = self.curve;
+
+        let output_img = ImageBuffer::from_fn(orig.width(), orig.height(), |x, y| {
+            let alpha = curve.apply(mask.get(x as usize, y as usize));
+            let foreground = orig.get_pixel(x, y).to_rgb();
+            foreground.map2(background.get_pixel(x, y), |fg, bg| {
+                (fg as f32 * alpha + bg as f32 * (1. - alpha)).round() as u8
+            })
+        });
+        DynamicImage::ImageRgb8(output_img)
+    }
+}
+
+pub fn replace_background(mask: &impl MaskSource, orig: &DynamicImage, background: Background) -> DynamicImage {
+    Compositor::new(background).composite(mask, orig)
+}
@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+#[cfg(feature = "tensorflow")]
+use tensorflow::Status;
+
+use crate::tfjs::ConvertError;
+
+#[derive(Debug)]
+pub enum BodyPixError {
+    MissingFile { path: PathBuf, source: std::io::Error },
+    #[cfg(feature = "tensorflow")]
+    InvalidGraph(Status),
+    MissingOperation(String),
+    #[cfg(feature = "tensorflow")]
+    Session(Status),
+    // From a backend other than Tensorflow
+    Backend(Box<dyn Error + Send + Sync>),
+    InvalidTensor(String),
+    UnsupportedImage(String),
+    Image(image::ImageError),
+    Io(std::io::Error),
+    ModelMismatch(String),
+    UnknownOperation { role: String, tried: Vec<String>, available: Vec<String> },
+    Convert(ConvertError),
+    WorkerStopped,
+}
+
+pub type Result<T> = std::result::Result<T, BodyPixError>;
+
+impl fmt::Display for BodyPixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyPixError::MissingFile { path, source } => {
+                write!(f, "Could not read model file {:?}: {}", path, source)
+            }
+            #[cfg(feature = "tensorflow")]
+            BodyPixError::InvalidGraph(status) => write!(f, "Invalid model graph: {}", status),
+            BodyPixError::MissingOperation(name) => {
+                write!(f, "Operation \"{}\" not found in model graph", name)
+            }
+            #[cfg(feature = "tensorflow")]
+            BodyPixError::Session(status) => write!(f, "Tensorflow session failed: {}", status),
+            BodyPixError::Backend(err) => write!(f, "Inference failed: {}", err),
+            BodyPixError::InvalidTensor(msg) => write!(f, "Invalid tensor: {}", msg),
+            BodyPixError::UnsupportedImage(msg) => write!(f, "Unsupported image: {}", msg),
+            BodyPixError::Image(err) => write!(f, "Image error: {}", err),
+            BodyPixError::Io(err) => write!(f, "I/O error: {}", err),
+            BodyPixError::ModelMismatch(msg) => write!(f, "Model does not match its spec: {}", msg),
+            BodyPixError::UnknownOperation { role, tried, available } => write!(
+                f,
+                "No {} operation in model graph (tried {}), available operations: {}",
+                role,
+                tried.join(", "),
+                available.join(", ")
+            ),
+            BodyPixError::Convert(err) => write!(f, "Model conversion failed: {}", err),
+            BodyPixError::WorkerStopped => write!(f, "The worker stopped before finishing the job"),
+        }
+    }
+}
+
+impl Error for BodyPixError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BodyPixError::MissingFile { source, .. } => Some(source),
+            #[cfg(feature = "tensorflow")]
+            BodyPixError::InvalidGraph(status) | BodyPixError::Session(status) => Some(status),
+            BodyPixError::Backend(err) => Some(err.as_ref()),
+            BodyPixError::Image(err) => Some(err),
+            BodyPixError::Io(err) => Some(err),
+            BodyPixError::Convert(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for BodyPixError {
+    fn from(err: image::ImageError) -> Self {
+        BodyPixError::Image(err)
+    }
+}
+
+impl From<ConvertError> for BodyPixError {
+    fn from(err: ConvertError) -> Self {
+        BodyPixError::Convert(err)
+    }
+}
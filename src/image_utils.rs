@@ -1,22 +1,34 @@
-use nannou::image;
-use nannou::image::{DynamicImage, GenericImageView, ImageBuffer, Pixel};
-use crate::bodypix::Segments;
+use std::path::Path;
 
-pub fn mask_to_image(mask: &Segments) -> DynamicImage {
-    let output_img = ImageBuffer::from_fn(mask.orig_width as u32, mask.orig_height as u32, |x, y| {
-        let shade_val: u8 = (mask.linear_mean(x as usize, y as usize) * 255.) as u8;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, RgbImage};
+use crate::error::{BodyPixError, Result};
+use crate::parts::{BodyPart, PartSegments};
+use crate::pose::{Pose, POSE_CHAIN};
+use crate::sampling::{MaskSource, Sampling};
+
+pub fn mask_to_image(mask: &impl MaskSource, sampling: Sampling) -> DynamicImage {
+    let mask = mask.to_mask(sampling, None);
+    let output_img = ImageBuffer::from_fn(mask.width as u32, mask.height as u32, |x, y| {
+        let shade_val: u8 = (mask.get(x as usize, y as usize) * 255.) as u8;
         image::Rgb([shade_val, shade_val, shade_val])
     });
 
     DynamicImage::ImageRgb8(output_img)
 }
 
-pub fn create_silhouette(mask: &Segments, orig: &DynamicImage) -> DynamicImage {
+// The people, on black. Pixels above the threshold count as a person.
+pub fn create_silhouette(
+    mask: &impl MaskSource,
+    orig: &DynamicImage,
+    threshold: f32,
+    sampling: Sampling,
+) -> DynamicImage {
+    let mask = mask.to_mask(sampling, Some(orig));
     let width = orig.width();
     let height = orig.height();
     let black_pixel = image::Rgb([0, 0, 0]);
     let silhouette_img = ImageBuffer::from_fn(width, height, |x, y| {
-        if mask.linear_mean(x as usize, y as usize) > 0.7 {
+        if mask.get(x as usize, y as usize) > threshold {
             orig.get_pixel(x, y).to_rgb()
         } else {
             black_pixel
@@ -25,12 +37,19 @@ pub fn create_silhouette(mask: &Segments, orig: &DynamicImage) -> DynamicImage {
     DynamicImage::ImageRgb8(silhouette_img)
 }
 
-pub fn create_cutout(mask: &Segments, orig: &DynamicImage) -> DynamicImage {
+// The background, with the people cut out in black
+pub fn create_cutout(
+    mask: &impl MaskSource,
+    orig: &DynamicImage,
+    threshold: f32,
+    sampling: Sampling,
+) -> DynamicImage {
+    let mask = mask.to_mask(sampling, Some(orig));
     let width = orig.width();
     let height = orig.height();
     let black_pixel = image::Rgb([0, 0, 0]);
     let silhouette_img = ImageBuffer::from_fn(width, height, |x, y| {
-        if mask.linear_mean(x as usize, y as usize) <= 0.7 {
+        if mask.get(x as usize, y as usize) <= threshold {
             orig.get_pixel(x, y).to_rgb()
         } else {
             black_pixel
@@ -38,3 +57,185 @@ pub fn create_cutout(mask: &Segments, orig: &DynamicImage) -> DynamicImage {
     });
     DynamicImage::ImageRgb8(silhouette_img)
 }
+
+// Maps the foreground probability of a pixel to its opacity
+#[derive(Clone, Copy, Debug, Default)]
+pub enum AlphaCurve {
+    #[default]
+    Linear,
+    // Fully opaque above the threshold, like create_silhouette
+    Threshold(f32),
+    // Transparent below low, opaque above high, linear in between
+    Ramp { low: f32, high: f32 },
+    // Like Ramp, but eased in and out for softer edges
+    SmoothStep { low: f32, high: f32 },
+    Gamma(f32),
+}
+
+impl AlphaCurve {
+    pub fn apply(self, probability: f32) -> f32 {
+        let probability = probability.clamp(0., 1.);
+        match self {
+            AlphaCurve::Linear => probability,
+            AlphaCurve::Threshold(threshold) => {
+                if probability > threshold {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            AlphaCurve::Ramp { low, high } => AlphaCurve::ramp(probability, low, high),
+            AlphaCurve::SmoothStep { low, high } => {
+                let t = AlphaCurve::ramp(probability, low, high);
+                t * t * (3. - 2. * t)
+            }
+            AlphaCurve::Gamma(gamma) => probability.powf(gamma),
+        }
+    }
+
+    fn ramp(probability: f32, low: f32, high: f32) -> f32 {
+        if high <= low {
+            return if probability > low { 1. } else { 0. };
+        }
+        ((probability - low) / (high - low)).clamp(0., 1.)
+    }
+}
+
+// The original colours, with the soft mask as alpha
+pub fn create_alpha_matte(
+    mask: &impl MaskSource,
+    orig: &DynamicImage,
+    curve: AlphaCurve,
+    sampling: Sampling,
+) -> DynamicImage {
+    let mask = mask.to_mask(sampling, Some(orig));
+    let width = orig.width();
+    let height = orig.height();
+    let matte_img = ImageBuffer::from_fn(width, height, |x, y| {
+        let image::Rgb([red, green, blue]) = orig.get_pixel(x, y).to_rgb();
+        let alpha = curve.apply(mask.get(x as usize, y as usize));
+        image::Rgba([red, green, blue, (alpha * 255.).round() as u8])
+    });
+    DynamicImage::ImageRgba8(matte_img)
+}
+
+// Saves keeping transparency. The format is picked from the file extension;
+// WebP needs the "webp" feature, as the image crate can't encode it.
+pub fn save_with_alpha(img: &DynamicImage, path: &Path) -> Result<()> {
+    let is_webp = path
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("webp"))
+        .unwrap_or(false);
+
+    #[cfg(feature = "webp")]
+    {
+        if is_webp {
+            let rgba = img.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode_lossless();
+            std::fs::write(path, &*encoded).map_err(|err| BodyPixError::Image(image::ImageError::IoError(err)))?;
+            return Ok(());
+        }
+    }
+
+    if is_webp && cfg!(not(feature = "webp")) {
+        return Err(BodyPixError::UnsupportedImage(
+            "saving WebP requires the \"webp\" feature".to_string(),
+        ));
+    }
+
+    img.save(path)?;
+    Ok(())
+}
+
+// Evenly spread hues, so that neighbouring part ids get distinct colours
+fn part_colour(part: BodyPart) -> image::Rgb<u8> {
+    let hue = part.id() as f32 * 360. / BodyPart::COUNT as f32;
+    let sector = hue / 60.;
+    let fraction = sector - sector.floor();
+    let rising = (fraction * 255.) as u8;
+    let falling = 255 - rising;
+    match sector as u32 {
+        0 => image::Rgb([255, rising, 0]),
+        1 => image::Rgb([falling, 255, 0]),
+        2 => image::Rgb([0, 255, rising]),
+        3 => image::Rgb([0, falling, 255]),
+        4 => image::Rgb([rising, 0, 255]),
+        _ => image::Rgb([255, 0, falling]),
+    }
+}
+
+pub fn parts_to_image(mask: &impl MaskSource, parts: &PartSegments, sampling: Sampling) -> DynamicImage {
+    let mask = mask.to_mask(sampling, None);
+    let black_pixel = image::Rgb([0, 0, 0]);
+    let output_img = ImageBuffer::from_fn(mask.width as u32, mask.height as u32, |x, y| {
+        match parts.part_in(&mask, 0.7, x as usize, y as usize) {
+            Some(part) => part_colour(part),
+            None => black_pixel,
+        }
+    });
+
+    DynamicImage::ImageRgb8(output_img)
+}
+
+pub fn blur_parts(
+    mask: &impl MaskSource,
+    parts: &PartSegments,
+    orig: &DynamicImage,
+    selected: &[BodyPart],
+    sigma: f32,
+    sampling: Sampling,
+) -> DynamicImage {
+    let mask = mask.to_mask(sampling, Some(orig));
+    let blurred = image::imageops::blur(orig, sigma);
+    let output_img = ImageBuffer::from_fn(orig.width(), orig.height(), |x, y| {
+        match parts.part_in(&mask, 0.7, x as usize, y as usize) {
+            Some(part) if selected.contains(&part) => blurred.get_pixel(x, y).to_rgb(),
+            _ => orig.get_pixel(x, y).to_rgb(),
+        }
+    });
+    DynamicImage::ImageRgb8(output_img)
+}
+
+fn put_pixel_checked(img: &mut RgbImage, x: i64, y: i64, pixel: image::Rgb<u8>) {
+    if x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+        img.put_pixel(x as u32, y as u32, pixel);
+    }
+}
+
+fn draw_line(img: &mut RgbImage, from: (f32, f32), to: (f32, f32), pixel: image::Rgb<u8>) {
+    let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil().max(1.) as i64;
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = from.0 + (to.0 - from.0) * t;
+        let y = from.1 + (to.1 - from.1) * t;
+        put_pixel_checked(img, x.round() as i64, y.round() as i64, pixel);
+    }
+}
+
+// Skeleton overlay, leaving out keypoints scoring below min_score
+pub fn draw_poses(orig: &DynamicImage, poses: &[Pose], min_score: f32) -> DynamicImage {
+    let mut output_img = orig.to_rgb8();
+    let line_pixel = image::Rgb([0, 255, 255]);
+    let point_pixel = image::Rgb([255, 0, 0]);
+
+    for pose in poses {
+        for (parent, child) in POSE_CHAIN.iter() {
+            let from = pose.keypoint(*parent);
+            let to = pose.keypoint(*child);
+            if from.score >= min_score && to.score >= min_score {
+                draw_line(&mut output_img, (from.x, from.y), (to.x, to.y), line_pixel);
+            }
+        }
+
+        for keypoint in pose.keypoints.iter().filter(|keypoint| keypoint.score >= min_score) {
+            let (x, y) = (keypoint.x.round() as i64, keypoint.y.round() as i64);
+            for dy in -2..=2 {
+                for dx in -2..=2 {
+                    put_pixel_checked(&mut output_img, x + dx, y + dy, point_pixel);
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgb8(output_img)
+}
@@ -0,0 +1,56 @@
+//! Person segmentation with the BodyPix models, running on Tensorflow or tract.
+//!
+//! The inference core lives here, separate from the nannou demo application,
+//! so it can be used from headless tools and services.
+
+pub mod backend;
+pub mod bodypix;
+pub mod compositor;
+pub mod error;
+pub mod image_utils;
+pub mod matting;
+pub mod models;
+pub mod multi_person;
+pub mod operations;
+pub mod options;
+pub mod parts;
+pub mod pool;
+pub mod pose;
+pub mod preprocessing;
+mod protobuf;
+pub mod refine;
+pub mod sampling;
+pub mod temporal;
+pub mod tfjs;
+pub mod video;
+
+#[cfg(feature = "tensorflow")]
+pub use crate::backend::TensorflowBackend;
+#[cfg(feature = "tract")]
+pub use crate::backend::TractBackend;
+pub use crate::backend::{OutputTensor, SegmentationBackend};
+pub use crate::bodypix::{BodyPix, ModelType, Segments};
+pub use crate::compositor::{replace_background, Background, Compositor};
+pub use crate::error::{BodyPixError, Result};
+pub use crate::image_utils::{
+    blur_parts, create_alpha_matte, create_cutout, create_silhouette, draw_poses, mask_to_image,
+    parts_to_image, save_with_alpha, AlphaCurve,
+};
+pub use crate::matting::Matting;
+pub use crate::models::{ModelSpec, Operations, MODELS};
+pub use crate::multi_person::{BoundingBox, MultiPersonConfig, PersonSegmentation};
+pub use crate::operations::GraphOperations;
+pub use crate::options::BodyPixOptions;
+pub use crate::parts::{BodyPart, PartSegments};
+pub use crate::pool::{BodyPixPool, JobHandle};
+pub use crate::pose::{Keypoint, KeypointType, MultiPoseConfig, Pose, POSE_CHAIN};
+pub use crate::preprocessing::{
+    prepare_input, InputConfig, InternalResolution, MobileNetNormalization, Normalization, ResNetNormalization,
+};
+pub use crate::refine::MaskRefiner;
+pub use crate::sampling::{Mask, MaskSource, Sampling};
+pub use crate::temporal::TemporalFilter;
+pub use crate::video::{FrameSource, LiveFrame, LivePipeline, VideoSink};
+
+// Re-exported so users get the same version of `image` as the library uses
+pub use image;
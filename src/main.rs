@@ -1,8 +1,5 @@
-mod bodypix;
-mod image_utils;
-
+use bodypix::video::open_source;
 use bodypix::*;
-use image_utils::*;
 
 use nannou::image::io::Reader as ImageReader;
 use nannou::prelude::*;
@@ -12,6 +9,7 @@ use nannou::wgpu::Texture;
 use dirs::picture_dir;
 use nannou::image::DynamicImage;
 use nannou::ui::widget::file_navigator::Types::WithExtension;
+use std::env;
 use std::path::PathBuf;
 use std::time::Instant;
 
@@ -25,6 +23,7 @@ widget_ids! {
         file_picker,
         process_image,
         bodypix_model,
+        toggle_live,
     }
 }
 
@@ -37,13 +36,18 @@ struct Model {
     show_file_picker: bool,
     image_file_path: PathBuf,
 
-    body_pix: Option<BodyPix>,
+    // Segments on a worker thread, so the UI stays responsive
+    pool: Option<BodyPixPool>,
+    pending: Option<(JobHandle<Segments>, Instant)>,
 
     selected_image: Option<DynamicImage>,
     image_texture: Option<Texture>,
     textures: Option<(Texture, Texture, Texture)>, // mask, silhouette, cutout
 
     selected_model: Option<usize>,
+
+    live: Option<LivePipeline>,
+    live_texture: Option<Texture>,
 }
 
 fn model<'a>(app: &App) -> Model {
@@ -78,13 +82,17 @@ fn model<'a>(app: &App) -> Model {
         show_file_picker: false,
         image_file_path: PathBuf::new(),
 
-        body_pix: (None),
+        pool: (None),
+        pending: (None),
 
         selected_image: (None),
         image_texture: (None),
         textures: (None),
 
         selected_model: (None),
+
+        live: (None),
+        live_texture: (None),
     }
 }
 
@@ -94,12 +102,15 @@ fn update(app: &App, model: &mut Model, _update: Update) {
         ids,
         show_file_picker,
         image_file_path,
-        body_pix,
+        pool,
+        pending,
 
         selected_image,
         image_texture,
 
         selected_model,
+        live,
+        live_texture,
         ..
     } = model;
 
@@ -157,50 +168,129 @@ fn update(app: &App, model: &mut Model, _update: Update) {
         }
     }
 
-    let bpx_model_data = BodyPix::models();
-    let model_drop_down = widget::DropDownList::new(bpx_model_data.0.as_ref(), *selected_model)
+    let model_labels: Vec<String> = BodyPix::models().iter().map(ModelSpec::label).collect();
+    let model_drop_down = widget::DropDownList::new(&model_labels, *selected_model)
         .w_h(200.0, 30.0)
         .label("BodyPix model");
 
     for selected_idx in model_drop_down.set(ids.bodypix_model, ui) {
         *selected_model = Some(selected_idx);
 
-        let model_file_name = bpx_model_data.1[selected_idx];
-        let stride = bpx_model_data.2[selected_idx];
+        let spec = &BodyPix::models()[selected_idx];
+        println!("Selected model: {}, {}", spec.file_name(), spec.stride);
 
-        println!("Selected model: {}, {}", model_file_name, stride);
+        let models_dir = app.assets_path().unwrap().join("models");
 
-        let model_path = app
-            .assets_path()
-            .unwrap()
-            .join("models")
-            .join(model_file_name);
+        *pool = match BodyPix::from_spec(&models_dir, spec) {
+            Ok(body_pix_init) => Some(BodyPixPool::new(body_pix_init, 1)),
+            Err(err) => {
+                eprintln!("Failed to load model: {}", err);
+                *selected_model = None;
+                None
+            }
+        };
+    }
 
-        let model_type = bpx_model_data.3[selected_idx];
-        let body_pix_init = BodyPix::from_model(model_path, stride, model_type);
+    let toggle_live_label = if live.is_some() {
+        "Stop live"
+    } else {
+        "Start live"
+    };
+    if (pool.is_some() || live.is_some())
+        && widget::Button::new()
+            .w_h(150., 30.)
+            .label(toggle_live_label)
+            .set(ids.toggle_live, ui)
+            .was_clicked()
+    {
+        match live.take() {
+            Some(pipeline) => {
+                let live_body_pix = pipeline.stop();
+                if pool.is_none() {
+                    *pool = live_body_pix.map(|body_pix| BodyPixPool::new(body_pix, 1));
+                }
+                *live_texture = None;
+                app.set_loop_mode(LoopMode::Wait);
+            }
+            None => {
+                // Set BODYPIX_FRAME_SOURCE to a directory of images or a .yuv file to run without a camera
+                let spec = env::var("BODYPIX_FRAME_SOURCE").unwrap_or_else(|_| "/dev/video0".to_string());
+                match open_source(&spec, 640, 480) {
+                    Ok(source) => {
+                        let compositor = Compositor::new(Background::Blur(10.));
+                        // Waits for an image being processed, then the live loop takes over the model
+                        if let Some(body_pix) = pool.take().unwrap().into_models().pop() {
+                            let filter = TemporalFilter::new().with_motion_compensation(4);
+                            *live = Some(LivePipeline::start_with_filter(source, body_pix, compositor, filter));
+                            app.set_loop_mode(LoopMode::RefreshSync);
+                        }
+                    }
+                    Err(err) => eprintln!("Failed to open frame source \"{}\": {}", spec, err),
+                }
+            }
+        }
+    }
+
+    if let Some(pipeline) = live.as_ref() {
+        match pipeline.try_latest() {
+            Some(Ok(frame)) => {
+                *image_texture = Some(Texture::from_image(app, &frame.original));
+                *live_texture = Some(Texture::from_image(app, &frame.composited));
+            }
+            Some(Err(err)) => eprintln!("Failed to process frame: {}", err),
+            None => {}
+        }
 
-        *body_pix = Some(body_pix_init);
+        if !pipeline.is_running() {
+            *pool = live
+                .take()
+                .unwrap()
+                .stop()
+                .map(|body_pix| BodyPixPool::new(body_pix, 1));
+            app.set_loop_mode(LoopMode::Wait);
+        }
     }
 
     if image_file_path.is_file()
-        && selected_model.is_some()
+        && pool.is_some()
+        && pending.is_none()
         && widget::Button::new()
             .label("Process image")
             .set(ids.process_image, ui)
             .was_clicked()
     {
-        let t = Instant::now();
+        let handle = pool.as_ref().unwrap().process_image(selected_image.clone().unwrap());
+        *pending = Some((handle, Instant::now()));
+        // Keep updating until the result is in
+        app.set_loop_mode(LoopMode::RefreshSync);
+    }
 
-        let mask = body_pix.as_ref().unwrap().process_image(
-            selected_image.as_ref().unwrap()
-        );
+    let result = match pending.as_ref() {
+        Some((handle, t)) => handle.try_get().map(|result| (result, *t)),
+        None => None,
+    };
+    if let Some((result, t)) = result {
+        *pending = None;
+        if live.is_none() {
+            app.set_loop_mode(LoopMode::Wait);
+        }
+
+        let mask = match result {
+            Ok(mask) => mask,
+            Err(err) => {
+                eprintln!("Failed to process image: {}", err);
+                return;
+            }
+        };
 
         let t_delta_1 = t.elapsed().as_micros() as f32 / 1000.;
         println!("Time to process: {}", t_delta_1);
 
-        let mask_image = mask_to_image(&mask);
-        let silhouette_image = create_silhouette(&mask, selected_image.as_ref().unwrap());
-        let cutout_image = create_cutout(&mask, selected_image.as_ref().unwrap());
+        let orig = selected_image.as_ref().unwrap();
+        let mask = mask.upsample(Sampling::Bilinear, Some(orig));
+        let mask_image = mask_to_image(&mask, Sampling::Bilinear);
+        let silhouette_image = create_silhouette(&mask, orig, 0.7, Sampling::Bilinear);
+        let cutout_image = create_cutout(&mask, orig, 0.7, Sampling::Bilinear);
 
         let t_delta_2 = t.elapsed().as_micros() as f32 / 1000. - t_delta_1;
         println!("Time to create images: {}", t_delta_2);
@@ -239,7 +329,14 @@ fn view(app: &App, model: &Model, frame: Frame) {
             .finish();
     }
 
-    if model.textures.is_some() {
+    if model.live_texture.is_some() {
+        let live_texture = model.live_texture.as_ref().unwrap();
+
+        draw.x_y(320., 240.)
+            .texture(&live_texture)
+            .w_h(400., 300.)
+            .finish();
+    } else if model.textures.is_some() {
         let (mask_texture, silhouette_texture, cutout_texture) = model.textures.as_ref().unwrap();
 
         draw.x_y(320., 240.)
@@ -0,0 +1,521 @@
+use image::{DynamicImage, GenericImageView};
+
+use crate::error::{BodyPixError, Result};
+use crate::refine::morphology;
+use crate::sampling::{Mask, MaskSource, Sampling};
+
+// Refines the coarse mask into an alpha matte that follows the fine detail of
+// the original image, like hair and fingers. The mask is split into a trimap:
+// sure foreground and background well inside and outside its edge, and an
+// unknown band along it. A guided filter transfers the edges of the image into
+// the mask, and for small images the closed-form matting solver (Levin et al.
+// 2008) can solve the unknown band exactly instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matting {
+    threshold: f32,
+    unknown_radius: usize, // Half the width of the unknown band, in pixels
+    radius: usize,         // Of the guided filter window
+    epsilon: f32,          // Regularization, in colours from 0 to 1
+    closed_form_max_pixels: usize,
+    iterations: usize, // Of the closed-form solver
+}
+
+impl Default for Matting {
+    fn default() -> Self {
+        Matting {
+            threshold: 0.7,
+            unknown_radius: 8,
+            radius: 8,
+            epsilon: 1e-4,
+            closed_form_max_pixels: 0,
+            iterations: 300,
+        }
+    }
+}
+
+// Trimap values
+const BACKGROUND: f32 = 0.;
+const UNKNOWN: f32 = 0.5;
+const FOREGROUND: f32 = 1.;
+
+fn is_unknown(trimap_value: f32) -> bool {
+    trimap_value > BACKGROUND && trimap_value < FOREGROUND
+}
+
+// How strongly the closed-form solver holds on to the known parts of the trimap
+const CONSTRAINT_WEIGHT: f64 = 100.;
+
+impl Matting {
+    pub fn new() -> Matting {
+        Matting::default()
+    }
+
+    // Pixels above this probability seed the foreground
+    pub fn with_threshold(mut self, threshold: f32) -> Matting {
+        self.threshold = threshold;
+        self
+    }
+
+    // How far from the edge of the mask alpha may change. Wider bands catch
+    // more hair, but let more of a similarly coloured background in.
+    pub fn with_unknown_radius(mut self, radius: usize) -> Matting {
+        self.unknown_radius = radius;
+        self
+    }
+
+    // Larger windows follow coarser structures of the image
+    pub fn with_radius(mut self, radius: usize) -> Matting {
+        self.radius = radius;
+        self
+    }
+
+    // Smaller values follow weaker edges in the image
+    pub fn with_epsilon(mut self, epsilon: f32) -> Matting {
+        self.epsilon = epsilon;
+        self
+    }
+
+    // Solves images up to this many pixels with closed-form matting, which is
+    // more accurate but much slower, and needs memory for every pixel
+    pub fn with_closed_form(mut self, max_pixels: usize) -> Matting {
+        self.closed_form_max_pixels = max_pixels;
+        self
+    }
+
+    pub fn with_iterations(mut self, iterations: usize) -> Matting {
+        self.iterations = iterations;
+        self
+    }
+
+    // Background, unknown and foreground as 0, 0.5 and 1
+    pub fn trimap(&self, mask: &impl MaskSource, orig: &DynamicImage, sampling: Sampling) -> Mask {
+        let mask = mask.to_mask(sampling, Some(orig));
+        let (width, height) = (mask.width, mask.height);
+        let foreground: Vec<bool> = mask.values.iter().map(|&value| value > self.threshold).collect();
+        let sure_foreground = morphology(&foreground, width, height, self.unknown_radius, false);
+        let maybe_foreground = morphology(&foreground, width, height, self.unknown_radius, true);
+
+        Mask {
+            width,
+            height,
+            values: sure_foreground
+                .iter()
+                .zip(&maybe_foreground)
+                .map(|(&sure, &maybe)| match (sure, maybe) {
+                    (true, _) => FOREGROUND,
+                    (false, true) => UNKNOWN,
+                    (false, false) => BACKGROUND,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn matte(&self, mask: &impl MaskSource, orig: &DynamicImage, sampling: Sampling) -> Result<Mask> {
+        let mask = mask.to_mask(sampling, Some(orig));
+        let guide = Guide::new(orig);
+        if (mask.width, mask.height) != (guide.width, guide.height) {
+            return Err(BodyPixError::UnsupportedImage(format!(
+                "the mask is {}x{}, but the image is {}x{}",
+                mask.width, mask.height, guide.width, guide.height
+            )));
+        }
+        let trimap = self.trimap(&*mask, orig, sampling);
+
+        let mut alpha = guided_filter(&guide, &mask.values, self.radius, self.epsilon as f64);
+        if guide.width * guide.height <= self.closed_form_max_pixels {
+            alpha = closed_form(&guide, &trimap.values, alpha, self.epsilon as f64, self.iterations);
+        }
+
+        // Only the unknown band may change
+        let values = alpha
+            .iter()
+            .zip(&trimap.values)
+            .map(|(&alpha, &known)| {
+                if is_unknown(known) {
+                    alpha.clamp(0., 1.) as f32
+                } else {
+                    known
+                }
+            })
+            .collect();
+        Ok(Mask {
+            width: guide.width,
+            height: guide.height,
+            values,
+        })
+    }
+}
+
+// The original image as colours from 0 to 1
+struct Guide {
+    width: usize,
+    height: usize,
+    colours: Vec<[f64; 3]>,
+}
+
+impl Guide {
+    fn new(orig: &DynamicImage) -> Guide {
+        let (width, height) = orig.dimensions();
+        let colours = orig
+            .to_rgb8()
+            .pixels()
+            .map(|pixel| [pixel[0] as f64 / 255., pixel[1] as f64 / 255., pixel[2] as f64 / 255.])
+            .collect();
+        Guide {
+            width: width as usize,
+            height: height as usize,
+            colours,
+        }
+    }
+}
+
+// Mean over the square of this radius around each pixel, through an integral
+// image so the radius doesn't change the cost
+fn box_mean(values: &[f64], width: usize, height: usize, radius: usize) -> Vec<f64> {
+    let mut integral = vec![0.; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row_sum = 0.;
+        for x in 0..width {
+            row_sum += values[x + y * width];
+            integral[(x + 1) + (y + 1) * (width + 1)] = integral[(x + 1) + y * (width + 1)] + row_sum;
+        }
+    }
+
+    let mut means = Vec::with_capacity(values.len());
+    for y in 0..height {
+        let (top, bottom) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+        for x in 0..width {
+            let (left, right) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+            let sum = integral[right + bottom * (width + 1)]
+                - integral[left + bottom * (width + 1)]
+                - integral[right + top * (width + 1)]
+                + integral[left + top * (width + 1)];
+            means.push(sum / ((right - left) * (bottom - top)) as f64);
+        }
+    }
+    means
+}
+
+// The upper triangle of a symmetric 3x3 matrix: xx, xy, xz, yy, yz, zz
+type Symmetric3 = [f64; 6];
+
+fn invert_symmetric(m: Symmetric3) -> Symmetric3 {
+    let [a, b, c, d, e, f] = m;
+    let inverse = [
+        d * f - e * e,
+        c * e - b * f,
+        b * e - c * d,
+        a * f - c * c,
+        b * c - a * e,
+        a * d - b * b,
+    ];
+    let determinant = a * inverse[0] + b * inverse[1] + c * inverse[2];
+    let scale = if determinant.abs() > f64::MIN_POSITIVE {
+        1. / determinant
+    } else {
+        0.
+    };
+    [
+        inverse[0] * scale,
+        inverse[1] * scale,
+        inverse[2] * scale,
+        inverse[3] * scale,
+        inverse[4] * scale,
+        inverse[5] * scale,
+    ]
+}
+
+fn multiply_symmetric(m: &Symmetric3, v: [f64; 3]) -> [f64; 3] {
+    [
+        m[0] * v[0] + m[1] * v[1] + m[2] * v[2],
+        m[1] * v[0] + m[3] * v[1] + m[4] * v[2],
+        m[2] * v[0] + m[4] * v[1] + m[5] * v[2],
+    ]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+// The guided filter with a colour guide (He et al. 2010): within each window
+// the output is a linear function of the colours, fitted to the mask
+fn guided_filter(guide: &Guide, mask: &[f32], radius: usize, epsilon: f64) -> Vec<f64> {
+    let (width, height) = (guide.width, guide.height);
+    let mean = |values: Vec<f64>| box_mean(&values, width, height, radius);
+    let channel = |c: usize| guide.colours.iter().map(|colour| colour[c]).collect::<Vec<f64>>();
+    let product = |c: usize, d: usize| {
+        guide
+            .colours
+            .iter()
+            .map(|colour| colour[c] * colour[d])
+            .collect::<Vec<f64>>()
+    };
+
+    let mask: Vec<f64> = mask.iter().map(|&value| value as f64).collect();
+    let mean_p = box_mean(&mask, width, height, radius);
+    let mean_i = [mean(channel(0)), mean(channel(1)), mean(channel(2))];
+    let mean_ip: Vec<Vec<f64>> = (0..3)
+        .map(|c| {
+            mean(
+                guide
+                    .colours
+                    .iter()
+                    .zip(&mask)
+                    .map(|(colour, p)| colour[c] * p)
+                    .collect(),
+            )
+        })
+        .collect();
+    let pairs = [(0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (2, 2)];
+    let mean_ii: Vec<Vec<f64>> = pairs.iter().map(|&(c, d)| mean(product(c, d))).collect();
+
+    let mut a: Vec<Vec<f64>> = (0..3).map(|_| Vec::with_capacity(mask.len())).collect();
+    let mut b = Vec::with_capacity(mask.len());
+    for i in 0..mask.len() {
+        let mu = [mean_i[0][i], mean_i[1][i], mean_i[2][i]];
+        let mut sigma = [0.; 6];
+        for (k, &(c, d)) in pairs.iter().enumerate() {
+            sigma[k] = mean_ii[k][i] - mu[c] * mu[d];
+        }
+        sigma[0] += epsilon;
+        sigma[3] += epsilon;
+        sigma[5] += epsilon;
+
+        let covariance = [
+            mean_ip[0][i] - mu[0] * mean_p[i],
+            mean_ip[1][i] - mu[1] * mean_p[i],
+            mean_ip[2][i] - mu[2] * mean_p[i],
+        ];
+        let coefficients = multiply_symmetric(&invert_symmetric(sigma), covariance);
+        for (a, coefficient) in a.iter_mut().zip(&coefficients) {
+            a.push(*coefficient);
+        }
+        b.push(mean_p[i] - dot(coefficients, mu));
+    }
+
+    let mean_a: Vec<Vec<f64>> = a.into_iter().map(mean).collect();
+    let mean_b = mean(b);
+    guide
+        .colours
+        .iter()
+        .enumerate()
+        .map(|(i, colour)| dot([mean_a[0][i], mean_a[1][i], mean_a[2][i]], *colour) + mean_b[i])
+        .collect()
+}
+
+// The colour statistics of a 3x3 window of the matting Laplacian
+struct Window {
+    mean: [f64; 3],
+    inverse_covariance: Symmetric3,
+}
+
+// The matting Laplacian L over all 3x3 windows, applied to a vector without
+// building the sparse matrix. For each window,
+// (Lx)_i += x_i - (sum x_j + (I_i - mean)^T inverse_covariance sum (I_j - mean) x_j) / 9
+struct Laplacian<'a> {
+    guide: &'a Guide,
+    windows: Vec<Window>, // One for every pixel not on the border
+}
+
+impl<'a> Laplacian<'a> {
+    fn new(guide: &'a Guide, epsilon: f64) -> Laplacian<'a> {
+        let (width, height) = (guide.width, guide.height);
+        let mut windows = Vec::with_capacity(width.saturating_sub(2) * height.saturating_sub(2));
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                let neighbours = Laplacian::neighbours(width, x, y);
+                let mut mean = [0.; 3];
+                for &j in &neighbours {
+                    for (sum, value) in mean.iter_mut().zip(&guide.colours[j]) {
+                        *sum += value / 9.;
+                    }
+                }
+
+                let mut covariance = [0.; 6];
+                for &j in &neighbours {
+                    let d = Laplacian::difference(guide.colours[j], mean);
+                    let products = [
+                        d[0] * d[0],
+                        d[0] * d[1],
+                        d[0] * d[2],
+                        d[1] * d[1],
+                        d[1] * d[2],
+                        d[2] * d[2],
+                    ];
+                    for (sum, product) in covariance.iter_mut().zip(&products) {
+                        *sum += product / 9.;
+                    }
+                }
+                covariance[0] += epsilon / 9.;
+                covariance[3] += epsilon / 9.;
+                covariance[5] += epsilon / 9.;
+
+                windows.push(Window {
+                    mean,
+                    inverse_covariance: invert_symmetric(covariance),
+                });
+            }
+        }
+        Laplacian { guide, windows }
+    }
+
+    fn neighbours(width: usize, x: usize, y: usize) -> [usize; 9] {
+        let mut neighbours = [0; 9];
+        for dy in 0..3 {
+            for dx in 0..3 {
+                neighbours[dx + dy * 3] = (x + dx - 1) + (y + dy - 1) * width;
+            }
+        }
+        neighbours
+    }
+
+    fn difference(colour: [f64; 3], mean: [f64; 3]) -> [f64; 3] {
+        [colour[0] - mean[0], colour[1] - mean[1], colour[2] - mean[2]]
+    }
+
+    fn apply(&self, x: &[f64], out: &mut [f64]) {
+        let width = self.guide.width;
+        let inner_width = width.saturating_sub(2);
+        for value in out.iter_mut() {
+            *value = 0.;
+        }
+
+        for (k, window) in self.windows.iter().enumerate() {
+            let neighbours = Laplacian::neighbours(width, 1 + k % inner_width, 1 + k / inner_width);
+            let mut sum = 0.;
+            let mut weighted = [0.; 3];
+            for &j in &neighbours {
+                let d = Laplacian::difference(self.guide.colours[j], window.mean);
+                sum += x[j];
+                for (weighted_channel, difference) in weighted.iter_mut().zip(&d) {
+                    *weighted_channel += difference * x[j];
+                }
+            }
+
+            let projected = multiply_symmetric(&window.inverse_covariance, weighted);
+            for &i in &neighbours {
+                let d = Laplacian::difference(self.guide.colours[i], window.mean);
+                out[i] += x[i] - (sum + dot(d, projected)) / 9.;
+            }
+        }
+    }
+}
+
+// Solves (L + lambda D) alpha = lambda D trimap, where D picks the known
+// pixels, by conjugate gradients starting from the guided filter's alpha
+fn closed_form(guide: &Guide, trimap: &[f32], initial: Vec<f64>, epsilon: f64, iterations: usize) -> Vec<f64> {
+    let laplacian = Laplacian::new(guide, epsilon);
+    let constraints: Vec<f64> = trimap
+        .iter()
+        .map(|&known| if is_unknown(known) { 0. } else { CONSTRAINT_WEIGHT })
+        .collect();
+    let system = |x: &[f64], out: &mut [f64]| {
+        laplacian.apply(x, out);
+        for ((value, constraint), x) in out.iter_mut().zip(&constraints).zip(x) {
+            *value += constraint * x;
+        }
+    };
+
+    let target: Vec<f64> = constraints
+        .iter()
+        .zip(trimap)
+        .map(|(constraint, &known)| constraint * known as f64)
+        .collect();
+    let mut alpha = initial;
+    let mut product = vec![0.; alpha.len()];
+    system(&alpha, &mut product);
+    let mut residual: Vec<f64> = target.iter().zip(&product).map(|(t, p)| t - p).collect();
+    let mut direction = residual.clone();
+    let mut residual_norm: f64 = residual.iter().map(|r| r * r).sum();
+    let tolerance = 1e-10 * target.iter().map(|t| t * t).sum::<f64>().max(1.);
+
+    for _ in 0..iterations {
+        if residual_norm <= tolerance {
+            break;
+        }
+        system(&direction, &mut product);
+        let curvature: f64 = direction.iter().zip(&product).map(|(d, p)| d * p).sum();
+        if curvature <= 0. {
+            break;
+        }
+        let step = residual_norm / curvature;
+        for (a, d) in alpha.iter_mut().zip(&direction) {
+            *a += step * d;
+        }
+        for (r, p) in residual.iter_mut().zip(&product) {
+            *r -= step * p;
+        }
+        let next_norm: f64 = residual.iter().map(|r| r * r).sum();
+        let beta = next_norm / residual_norm;
+        for (d, r) in direction.iter_mut().zip(&residual) {
+            *d = r + beta * *d;
+        }
+        residual_norm = next_norm;
+    }
+    alpha
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    // Black on the left, white from x = 8, with the mask edge two pixels short of it
+    fn step_edge() -> (Mask, DynamicImage) {
+        let image = GrayImage::from_fn(16, 8, |x, _| Luma([if x < 8 { 0 } else { 255 }]));
+        let mask = Mask::from_fn(16, 8, |x, _| if x < 6 { 0. } else { 1. });
+        (mask, DynamicImage::ImageLuma8(image))
+    }
+
+    fn row(mask: &Mask) -> &[f32] {
+        &mask.values[4 * mask.width..5 * mask.width]
+    }
+
+    #[test]
+    fn trimap_has_an_unknown_band_along_the_edge() {
+        let (mask, image) = step_edge();
+        let trimap = Matting::new().with_unknown_radius(2).trimap(&mask, &image, Sampling::Nearest);
+        let mut expected = vec![BACKGROUND; 4];
+        expected.extend_from_slice(&[UNKNOWN; 4]);
+        expected.extend_from_slice(&[FOREGROUND; 8]);
+        assert_eq!(row(&trimap), &expected[..]);
+    }
+
+    #[test]
+    fn guided_filter_sharpens_the_edge() {
+        let (_, image) = step_edge();
+        // Blurred across the edge, like a mask sampled from the coarse grid
+        let mask = Mask::from_fn(16, 8, |x, _| ((x as f32 - 3.5) / 8.).clamp(0., 1.));
+        let matting = Matting::new().with_threshold(0.5).with_unknown_radius(3).with_radius(2);
+        let alpha = matting.matte(&mask, &image, Sampling::Nearest).unwrap();
+        let (before, after) = (row(&mask), row(&alpha));
+
+        // Steeper across the edge of the image, flatter on either side of it
+        assert!(after[8] - after[7] > 2. * (before[8] - before[7]));
+        assert!(after[7] - after[6] < before[7] - before[6]);
+        assert!(after[9] - after[8] < before[9] - before[8]);
+        assert!((after[7] + after[8] - 1.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn closed_form_moves_the_edge_to_the_image() {
+        let (mask, image) = step_edge();
+        let matting = Matting::new().with_unknown_radius(3).with_closed_form(16 * 8);
+        let alpha = matting.matte(&mask, &image, Sampling::Nearest).unwrap();
+
+        // The unknown band, from x = 3 to 8, is solved to the colours
+        for (x, &value) in row(&alpha).iter().enumerate() {
+            if x < 8 {
+                assert!(value < 0.01, "alpha {} at {}", value, x);
+            } else {
+                assert!(value > 0.99, "alpha {} at {}", value, x);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_mask_of_another_size() {
+        let (_, image) = step_edge();
+        assert!(Matting::new().matte(&Mask::new(8, 4), &image, Sampling::Nearest).is_err());
+    }
+}
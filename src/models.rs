@@ -0,0 +1,150 @@
+// The BodyPix models we know about. This is the single source of truth for
+// which models bodypix-models installs, what the applications list, and how
+// the library loads them.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelType {
+    MobileNet,
+    ResNet,
+}
+
+// Names of the operations to feed and fetch in a converted graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operations {
+    pub input: &'static str,
+    pub segments: &'static str,
+    pub part_heatmaps: &'static str,
+    pub heatmaps: &'static str,
+    pub short_offsets: &'static str,
+    pub long_offsets: &'static str,
+    // The displacement outputs have no friendly names in the converted graphs
+    pub displacement_fwd: &'static str,
+    pub displacement_bwd: &'static str,
+}
+
+pub const MOBILENET_OPERATIONS: Operations = Operations {
+    input: "sub_2",
+    segments: "float_segments",
+    part_heatmaps: "float_part_heatmaps",
+    heatmaps: "float_heatmaps",
+    short_offsets: "float_short_offsets",
+    long_offsets: "float_long_offsets",
+    displacement_fwd: "MobilenetV1/displacement_fwd_2/BiasAdd",
+    displacement_bwd: "MobilenetV1/displacement_bwd_2/BiasAdd",
+};
+
+pub const RESNET_OPERATIONS: Operations = Operations {
+    input: "sub_2",
+    segments: "float_segments",
+    part_heatmaps: "float_part_heatmaps",
+    heatmaps: "float_heatmaps",
+    short_offsets: "float_short_offsets",
+    long_offsets: "float_long_offsets",
+    displacement_fwd: "resnet_v1_50/displacement_fwd_2/BiasAdd",
+    displacement_bwd: "resnet_v1_50/displacement_bwd_2/BiasAdd",
+};
+
+impl ModelType {
+    pub const fn default_operations(self) -> Operations {
+        match self {
+            ModelType::MobileNet => MOBILENET_OPERATIONS,
+            ModelType::ResNet => RESNET_OPERATIONS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelSpec {
+    pub model_type: ModelType,
+    pub multiplier: f32, // MobileNet width multiplier, 1.0 for ResNet
+    pub stride: u32,
+    pub quant_bytes: u32, // 4 for float weights, 2 or 1 for quantized
+    // Exact operation names, for graphs where looking them up doesn't work
+    pub operations: Option<Operations>,
+}
+
+const fn spec(model_type: ModelType, multiplier: f32, stride: u32) -> ModelSpec {
+    ModelSpec {
+        model_type,
+        multiplier,
+        stride,
+        quant_bytes: 4,
+        operations: None,
+    }
+}
+
+pub const MODELS: [ModelSpec; 8] = [
+    spec(ModelType::MobileNet, 0.5, 8),
+    spec(ModelType::MobileNet, 0.5, 16),
+    spec(ModelType::MobileNet, 0.75, 8),
+    spec(ModelType::MobileNet, 0.75, 16),
+    spec(ModelType::MobileNet, 1.0, 8),
+    spec(ModelType::MobileNet, 1.0, 16),
+    spec(ModelType::ResNet, 1.0, 16),
+    spec(ModelType::ResNet, 1.0, 32),
+];
+
+impl ModelSpec {
+    fn quant_name(&self) -> &'static str {
+        match self.quant_bytes {
+            1 => "quant1",
+            2 => "quant2",
+            _ => "float",
+        }
+    }
+
+    fn multiplier_percent(&self) -> u32 {
+        (self.multiplier * 100.).round() as u32
+    }
+
+    // Where the model lives below the tfjs-models storage root
+    pub fn url_path(&self) -> String {
+        match self.model_type {
+            ModelType::MobileNet => format!(
+                "bodypix/mobilenet/{}/{:03}",
+                self.quant_name(),
+                self.multiplier_percent()
+            ),
+            ModelType::ResNet => format!("bodypix/resnet50/{}", self.quant_name()),
+        }
+    }
+
+    // The tfjs graph for this stride, in the url_path directory
+    pub fn model_json(&self) -> String {
+        format!("model-stride{}.json", self.stride)
+    }
+
+    // The converted frozen graph, in the models directory
+    pub fn file_name(&self) -> String {
+        format!("{}-stride{}.pb", self.url_path().replace('/', "_"), self.stride)
+    }
+
+    // For menus
+    pub fn label(&self) -> String {
+        let label = match self.model_type {
+            ModelType::MobileNet => format!("MobileNet {:.2} stride {}", self.multiplier, self.stride),
+            ModelType::ResNet => format!("ResNet 50 stride {}", self.stride),
+        };
+        match self.quant_bytes {
+            4 => label,
+            bytes => format!("{}, {} byte weights", label, bytes),
+        }
+    }
+
+    // For command lines and config files, e.g. "mobilenet-075-stride16", or
+    // "mobilenet-075-stride16-quant2" for weights quantized to 2 bytes
+    pub fn name(&self) -> String {
+        let name = match self.model_type {
+            ModelType::MobileNet => format!("mobilenet-{:03}-stride{}", self.multiplier_percent(), self.stride),
+            ModelType::ResNet => format!("resnet50-stride{}", self.stride),
+        };
+        match self.quant_bytes {
+            4 => name,
+            _ => format!("{}-{}", name, self.quant_name()),
+        }
+    }
+
+    pub fn find(name: &str) -> Option<&'static ModelSpec> {
+        MODELS.iter().find(|spec| spec.name() == name)
+    }
+}
@@ -0,0 +1,187 @@
+use crate::bodypix::Segments;
+use crate::pose::{Grid, KeypointType, MultiPoseConfig, Pose};
+
+#[derive(Clone, Copy, Debug)]
+pub struct MultiPersonConfig {
+    pub pose: MultiPoseConfig,
+    pub segmentation_threshold: f32,
+    pub min_keypoint_score: f32, // Keypoints below this don't take part in matching
+    pub refine_steps: usize,
+}
+
+impl Default for MultiPersonConfig {
+    fn default() -> Self {
+        MultiPersonConfig {
+            pose: MultiPoseConfig::default(),
+            segmentation_threshold: 0.7,
+            min_keypoint_score: 0.3,
+            refine_steps: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct PersonSegmentation {
+    pub mask: Segments, // Zero outside of this person
+    pub bounding_box: BoundingBox,
+    pub pose: Pose,
+}
+
+// Where the long-range offsets at a cell say that a keypoint of the same
+// person is, following the offsets a few times to refine the estimate.
+// Like the poses it is matched to, this is in network input coordinates.
+fn embedding(
+    long_offsets: &Grid,
+    segments: &Segments,
+    cell_x: usize,
+    cell_y: usize,
+    part: usize,
+    refine_steps: usize,
+) -> (f32, f32) {
+    let (min_x, min_y, max_x, max_y) = segments.transform.input_bounds();
+
+    let (offset_x, offset_y) = long_offsets.vector(cell_y, cell_x, part);
+    let mut x = (cell_x as u32 * segments.stride) as f32 + offset_x;
+    let mut y = (cell_y as u32 * segments.stride) as f32 + offset_y;
+
+    for _ in 0..refine_steps {
+        x = x.max(min_x).min(max_x);
+        y = y.max(min_y).min(max_y);
+        let (cell_x, cell_y) = long_offsets.nearest_cell(segments.stride, x, y);
+        let (offset_x, offset_y) = long_offsets.vector(cell_y, cell_x, part);
+        x += offset_x;
+        y += offset_y;
+    }
+
+    (x, y)
+}
+
+// Mean squared distance between the embedding of a cell and the keypoints of a pose
+fn pose_distance(embeddings: &[Option<(f32, f32)>], pose: &Pose, min_keypoint_score: f32) -> f32 {
+    let mut distance = 0.;
+    let mut matched = 0;
+    for (keypoint, embedding) in pose.keypoints.iter().zip(embeddings) {
+        if let Some((x, y)) = embedding {
+            if keypoint.score > min_keypoint_score {
+                distance += (x - keypoint.x) * (x - keypoint.x) + (y - keypoint.y) * (y - keypoint.y);
+                matched += 1;
+            }
+        }
+    }
+
+    if matched == 0 {
+        f32::INFINITY
+    } else {
+        distance / matched as f32
+    }
+}
+
+pub(crate) fn segment_people(
+    segments: &Segments,
+    poses: Vec<Pose>,
+    long_offsets: &Grid,
+    config: &MultiPersonConfig,
+) -> Vec<PersonSegmentation> {
+    // Only follow the offsets of keypoints that some pose can be matched on
+    let used_parts: Vec<bool> = (0..KeypointType::COUNT)
+        .map(|part| poses.iter().any(|pose| pose.keypoints[part].score > config.min_keypoint_score))
+        .collect();
+
+    let cells = segments.width * segments.height;
+    let mut masks = vec![vec![0f32; cells]; poses.len()];
+    let mut cell_bounds: Vec<Option<(usize, usize, usize, usize)>> = vec![None; poses.len()];
+
+    for cell_y in 0..segments.height {
+        for cell_x in 0..segments.width {
+            let cell = cell_x + cell_y * segments.width;
+            let value = segments.values[cell];
+            if value <= config.segmentation_threshold {
+                continue;
+            }
+
+            let embeddings: Vec<Option<(f32, f32)>> = used_parts
+                .iter()
+                .enumerate()
+                .map(|(part, used)| {
+                    if *used {
+                        Some(embedding(long_offsets, segments, cell_x, cell_y, part, config.refine_steps))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            let closest = poses
+                .iter()
+                .map(|pose| pose_distance(&embeddings, pose, config.min_keypoint_score))
+                .enumerate()
+                .filter(|(_, distance)| distance.is_finite())
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+            if let Some((person, _)) = closest {
+                masks[person][cell] = value;
+                cell_bounds[person] = Some(match cell_bounds[person] {
+                    None => (cell_x, cell_y, cell_x, cell_y),
+                    Some((min_x, min_y, max_x, max_y)) => (
+                        min_x.min(cell_x),
+                        min_y.min(cell_y),
+                        max_x.max(cell_x),
+                        max_y.max(cell_y),
+                    ),
+                });
+            }
+        }
+    }
+
+    // Poses that no pixels were assigned to are left out
+    poses
+        .into_iter()
+        .zip(masks)
+        .zip(cell_bounds)
+        .filter_map(|((pose, values), bounds)| {
+            let (min_x, min_y, max_x, max_y) = bounds?;
+            Some(PersonSegmentation {
+                bounding_box: cell_bounding_box(segments, min_x, min_y, max_x, max_y),
+                mask: Segments {
+                    orig_width: segments.orig_width,
+                    orig_height: segments.orig_height,
+                    width: segments.width,
+                    height: segments.height,
+                    stride: segments.stride,
+                    values,
+                    transform: segments.transform,
+                },
+                pose: segments.transform.pose_to_original(pose),
+            })
+        })
+        .collect()
+}
+
+// Pixels of the original image covered by a range of cells
+fn cell_bounding_box(segments: &Segments, min_x: usize, min_y: usize, max_x: usize, max_y: usize) -> BoundingBox {
+    let stride = segments.stride as f32;
+    let half_stride = (segments.stride / 2) as f32;
+    let transform = &segments.transform;
+    let (left, top) = transform.to_original(min_x as f32 * stride - half_stride, min_y as f32 * stride - half_stride);
+    let (right, bottom) = transform.to_original(max_x as f32 * stride + half_stride, max_y as f32 * stride + half_stride);
+
+    let right = right.round().max(0.).min((segments.orig_width - 1) as f32) as usize;
+    let bottom = bottom.round().max(0.).min((segments.orig_height - 1) as f32) as usize;
+    // The output grid reaches a little past the image, so clamp the start too
+    let left = std::cmp::min(left.round().max(0.) as usize, right);
+    let top = std::cmp::min(top.round().max(0.) as usize, bottom);
+
+    BoundingBox {
+        x: left as u32,
+        y: top as u32,
+        width: (right + 1 - left) as u32,
+        height: (bottom + 1 - top) as u32,
+    }
+}
@@ -0,0 +1,212 @@
+#[cfg(feature = "tensorflow")]
+use std::collections::HashMap;
+
+#[cfg(feature = "tensorflow")]
+use tensorflow::{SignatureDef, TensorInfo};
+
+use crate::backend::SegmentationBackend;
+use crate::error::{BodyPixError, Result};
+use crate::models::{ModelType, Operations};
+
+// The operations a loaded graph is fed and fetched through. Graphs from other
+// converter versions name things differently, so these are looked up in the
+// graph rather than assumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GraphOperations {
+    pub input: String,
+    pub segments: String,
+    // Only needed for parts and poses, so a graph without them still segments
+    pub part_heatmaps: Option<String>,
+    pub heatmaps: Option<String>,
+    pub short_offsets: Option<String>,
+    pub long_offsets: Option<String>,
+    pub displacement_fwd: Option<String>,
+    pub displacement_bwd: Option<String>,
+}
+
+// Names seen in converted graphs, most likely first
+const INPUT_NAMES: [&str; 3] = ["sub_2", "input", "image"];
+const SEGMENTS_NAMES: [&str; 2] = ["float_segments", "segments"];
+pub(crate) const PART_HEATMAPS_NAMES: [&str; 2] = ["float_part_heatmaps", "part_heatmaps"];
+pub(crate) const HEATMAPS_NAMES: [&str; 2] = ["float_heatmaps", "heatmaps"];
+pub(crate) const SHORT_OFFSETS_NAMES: [&str; 2] = ["float_short_offsets", "short_offsets"];
+pub(crate) const LONG_OFFSETS_NAMES: [&str; 2] = ["float_long_offsets", "long_offsets"];
+pub(crate) const DISPLACEMENT_FWD_NAMES: [&str; 3] = [
+    "displacement_fwd_2/BiasAdd",
+    "displacement_fwd",
+    "float_displacement_fwd",
+];
+pub(crate) const DISPLACEMENT_BWD_NAMES: [&str; 3] = [
+    "displacement_bwd_2/BiasAdd",
+    "displacement_bwd",
+    "float_displacement_bwd",
+];
+
+impl GraphOperations {
+    // Uses the names in overrides as they are, or searches the graph for known names
+    pub(crate) fn resolve(
+        backend: &dyn SegmentationBackend,
+        model_type: ModelType,
+        overrides: Option<&Operations>,
+    ) -> Result<GraphOperations> {
+        let graph_names = backend.operation_names();
+        if let Some(operations) = overrides {
+            return GraphOperations::from_overrides(&graph_names, operations);
+        }
+
+        let defaults = model_type.default_operations();
+
+        let input = match find(&graph_names, defaults.input, &INPUT_NAMES) {
+            Some(name) => name,
+            None => only_input(backend).ok_or_else(|| not_found(&graph_names, "input", &INPUT_NAMES))?,
+        };
+        let segments = find(&graph_names, defaults.segments, &SEGMENTS_NAMES)
+            .ok_or_else(|| not_found(&graph_names, "segments", &SEGMENTS_NAMES))?;
+
+        Ok(GraphOperations {
+            input,
+            segments,
+            part_heatmaps: find(&graph_names, defaults.part_heatmaps, &PART_HEATMAPS_NAMES),
+            heatmaps: find(&graph_names, defaults.heatmaps, &HEATMAPS_NAMES),
+            short_offsets: find(&graph_names, defaults.short_offsets, &SHORT_OFFSETS_NAMES),
+            long_offsets: find(&graph_names, defaults.long_offsets, &LONG_OFFSETS_NAMES),
+            displacement_fwd: find(&graph_names, defaults.displacement_fwd, &DISPLACEMENT_FWD_NAMES),
+            displacement_bwd: find(&graph_names, defaults.displacement_bwd, &DISPLACEMENT_BWD_NAMES),
+        })
+    }
+
+    fn from_overrides(graph_names: &[String], operations: &Operations) -> Result<GraphOperations> {
+        let has = |name: &str| graph_names.iter().any(|graph_name| graph_name == name);
+        let required = |role: &str, name: &str| {
+            if has(name) {
+                Ok(name.to_string())
+            } else {
+                Err(not_found(graph_names, role, &[name]))
+            }
+        };
+        let optional = |name: &str| if has(name) { Some(name.to_string()) } else { None };
+
+        Ok(GraphOperations {
+            input: required("input", operations.input)?,
+            segments: required("segments", operations.segments)?,
+            part_heatmaps: optional(operations.part_heatmaps),
+            heatmaps: optional(operations.heatmaps),
+            short_offsets: optional(operations.short_offsets),
+            long_offsets: optional(operations.long_offsets),
+            displacement_fwd: optional(operations.displacement_fwd),
+            displacement_bwd: optional(operations.displacement_bwd),
+        })
+    }
+
+    // Binds to the named inputs and outputs of a SavedModel signature, which
+    // are matched by key the same way as operation names
+    #[cfg(feature = "tensorflow")]
+    pub(crate) fn from_signature(signature: &SignatureDef) -> Result<GraphOperations> {
+        let inputs = signature.inputs();
+        let outputs = signature.outputs();
+        let input_keys: Vec<String> = inputs.keys().cloned().collect();
+        let output_keys: Vec<String> = outputs.keys().cloned().collect();
+
+        let input_key = match find(&input_keys, INPUT_NAMES[0], &INPUT_NAMES) {
+            Some(key) => key,
+            None if input_keys.len() == 1 => input_keys[0].clone(),
+            None => return Err(signature_not_found("input", &INPUT_NAMES, &input_keys)),
+        };
+        let segments_key = find(&output_keys, SEGMENTS_NAMES[0], &SEGMENTS_NAMES)
+            .ok_or_else(|| signature_not_found("segments", &SEGMENTS_NAMES, &output_keys))?;
+
+        let optional = |known: &[&str]| -> Result<Option<String>> {
+            find(&output_keys, known[0], known)
+                .map(|key| tensor_operation(outputs, &key))
+                .transpose()
+        };
+
+        Ok(GraphOperations {
+            input: tensor_operation(inputs, &input_key)?,
+            segments: tensor_operation(outputs, &segments_key)?,
+            part_heatmaps: optional(&PART_HEATMAPS_NAMES)?,
+            heatmaps: optional(&HEATMAPS_NAMES)?,
+            short_offsets: optional(&SHORT_OFFSETS_NAMES)?,
+            long_offsets: optional(&LONG_OFFSETS_NAMES)?,
+            displacement_fwd: optional(&DISPLACEMENT_FWD_NAMES)?,
+            displacement_bwd: optional(&DISPLACEMENT_BWD_NAMES)?,
+        })
+    }
+}
+
+// The operation behind a signature key. Inputs are fed and outputs fetched at
+// index 0, so tensors at other indices can't be bound.
+#[cfg(feature = "tensorflow")]
+fn tensor_operation(tensors: &HashMap<String, TensorInfo>, key: &str) -> Result<String> {
+    let tensor = tensors[key].name();
+    if tensor.index != 0 {
+        return Err(BodyPixError::ModelMismatch(format!(
+            "signature key \"{}\" is output {} of \"{}\", only output 0 is supported",
+            key, tensor.index, tensor.name
+        )));
+    }
+    Ok(tensor.name.clone())
+}
+
+#[cfg(feature = "tensorflow")]
+fn signature_not_found(role: &str, tried: &[&str], keys: &[String]) -> BodyPixError {
+    BodyPixError::UnknownOperation {
+        role: role.to_string(),
+        tried: tried.iter().map(|name| name.to_string()).collect(),
+        available: keys.to_vec(),
+    }
+}
+
+// The ResNet graphs keep their layers in a "resnet_v1_50" name scope
+pub(crate) fn detect_model_type(backend: &dyn SegmentationBackend) -> ModelType {
+    let is_resnet = backend
+        .operation_names()
+        .iter()
+        .any(|name| name.starts_with("resnet_v1_50/"));
+
+    if is_resnet {
+        ModelType::ResNet
+    } else {
+        ModelType::MobileNet
+    }
+}
+
+// An exact match for the default name or any known name, or else an operation
+// with a known name inside a name scope, like "model/float_segments"
+fn find(graph_names: &[String], default: &str, known: &[&str]) -> Option<String> {
+    let candidates: Vec<&str> = std::iter::once(default).chain(known.iter().copied()).collect();
+
+    for candidate in &candidates {
+        if graph_names.iter().any(|name| name == candidate) {
+            return Some(candidate.to_string());
+        }
+    }
+
+    for candidate in &candidates {
+        let suffix = format!("/{}", candidate);
+        if let Some(name) = graph_names.iter().find(|name| name.ends_with(&suffix)) {
+            return Some(name.clone());
+        }
+    }
+
+    None
+}
+
+// The model's only input, if it has exactly one
+fn only_input(backend: &dyn SegmentationBackend) -> Option<String> {
+    let inputs = backend.input_names();
+    if inputs.len() == 1 {
+        inputs.into_iter().next()
+    } else {
+        None
+    }
+}
+
+// The backends list everything but the weights, which would drown out the useful names
+pub(crate) fn not_found(graph_names: &[String], role: &str, tried: &[&str]) -> BodyPixError {
+    BodyPixError::UnknownOperation {
+        role: role.to_string(),
+        tried: tried.iter().map(|name| name.to_string()).collect(),
+        available: graph_names.to_vec(),
+    }
+}
@@ -0,0 +1,110 @@
+#[cfg(feature = "tensorflow")]
+use tensorflow::SessionOptions;
+
+#[cfg(feature = "tensorflow")]
+use crate::error::{BodyPixError, Result};
+use crate::protobuf::Writer;
+
+// How the Tensorflow session of a model is set up. Unset options are left to
+// Tensorflow, which uses all cores and runs every graph optimization. Other
+// backends ignore them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BodyPixOptions {
+    intra_op_threads: Option<u32>,
+    inter_op_threads: Option<u32>,
+    graph_optimizations: Option<bool>,
+    cpu_only: bool,
+    config_proto: Vec<u8>,
+}
+
+// Field numbers in tensorflow/core/protobuf/config.proto and rewriter_config.proto
+const CONFIG_DEVICE_COUNT: u32 = 1;
+const CONFIG_INTRA_OP_THREADS: u32 = 2;
+const CONFIG_INTER_OP_THREADS: u32 = 5;
+const CONFIG_GRAPH_OPTIONS: u32 = 10;
+const GRAPH_OPTIMIZER_OPTIONS: u32 = 3;
+const GRAPH_REWRITE_OPTIONS: u32 = 10;
+const OPTIMIZER_OPT_LEVEL: u32 = 3;
+const OPT_LEVEL_L0: i64 = -1;
+const REWRITER_DISABLE_META_OPTIMIZER: u32 = 19;
+
+impl BodyPixOptions {
+    pub fn new() -> BodyPixOptions {
+        BodyPixOptions::default()
+    }
+
+    // Threads used within one operation, like a large convolution
+    pub fn with_intra_op_threads(mut self, threads: u32) -> BodyPixOptions {
+        self.intra_op_threads = Some(threads);
+        self
+    }
+
+    // Operations that may run at the same time
+    pub fn with_inter_op_threads(mut self, threads: u32) -> BodyPixOptions {
+        self.inter_op_threads = Some(threads);
+        self
+    }
+
+    // Grappler's graph rewrites and the classic constant folding. Turning them
+    // off makes loading faster, at some cost to inference time.
+    pub fn with_graph_optimizations(mut self, enabled: bool) -> BodyPixOptions {
+        self.graph_optimizations = Some(enabled);
+        self
+    }
+
+    // Keeps the session off any GPU, even in builds with the gpu feature
+    pub fn with_cpu_only(mut self, cpu_only: bool) -> BodyPixOptions {
+        self.cpu_only = cpu_only;
+        self
+    }
+
+    // A serialized ConfigProto for anything else. It is merged over the
+    // options above, so the fields it sets win.
+    pub fn with_config_proto(mut self, config_proto: Vec<u8>) -> BodyPixOptions {
+        self.config_proto = config_proto;
+        self
+    }
+
+    // The ConfigProto the session is created with
+    pub fn config_proto(&self) -> Vec<u8> {
+        let mut config = Writer::new();
+
+        if self.cpu_only {
+            let mut device_count = Writer::new();
+            device_count.string(1, "GPU");
+            device_count.varint_field(2, 0);
+            config.bytes(CONFIG_DEVICE_COUNT, &device_count.0);
+        }
+        if let Some(threads) = self.intra_op_threads {
+            config.varint_field(CONFIG_INTRA_OP_THREADS, threads as u64);
+        }
+        if let Some(threads) = self.inter_op_threads {
+            config.varint_field(CONFIG_INTER_OP_THREADS, threads as u64);
+        }
+        if self.graph_optimizations == Some(false) {
+            let mut optimizer_options = Writer::new();
+            optimizer_options.varint_field(OPTIMIZER_OPT_LEVEL, OPT_LEVEL_L0 as u64);
+            let mut rewrite_options = Writer::new();
+            rewrite_options.varint_field(REWRITER_DISABLE_META_OPTIMIZER, 1);
+
+            let mut graph_options = Writer::new();
+            graph_options.bytes(GRAPH_OPTIMIZER_OPTIONS, &optimizer_options.0);
+            graph_options.bytes(GRAPH_REWRITE_OPTIONS, &rewrite_options.0);
+            config.bytes(CONFIG_GRAPH_OPTIONS, &graph_options.0);
+        }
+
+        // Protobuf merges a message written twice, with later fields winning
+        config.0.extend_from_slice(&self.config_proto);
+        config.0
+    }
+
+    #[cfg(feature = "tensorflow")]
+    pub(crate) fn session_options(&self) -> Result<SessionOptions> {
+        let mut options = SessionOptions::new();
+        let config = self.config_proto();
+        if !config.is_empty() {
+            options.set_config(&config).map_err(BodyPixError::Session)?;
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+
+#[cfg(feature = "tensorflow")]
+use tensorflow::Tensor;
+
+use crate::backend::OutputTensor;
+use crate::bodypix::Segments;
+use crate::error::{BodyPixError, Result};
+use crate::preprocessing::InputTransform;
+use crate::sampling::Mask;
+
+// Part ids, in the channel order of the "float_part_heatmaps" output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BodyPart {
+    LeftFace = 0,
+    RightFace,
+    LeftUpperArmFront,
+    LeftUpperArmBack,
+    RightUpperArmFront,
+    RightUpperArmBack,
+    LeftLowerArmFront,
+    LeftLowerArmBack,
+    RightLowerArmFront,
+    RightLowerArmBack,
+    LeftHand,
+    RightHand,
+    TorsoFront,
+    TorsoBack,
+    LeftUpperLegFront,
+    LeftUpperLegBack,
+    RightUpperLegFront,
+    RightUpperLegBack,
+    LeftLowerLegFront,
+    LeftLowerLegBack,
+    RightLowerLegFront,
+    RightLowerLegBack,
+    LeftFoot,
+    RightFoot,
+}
+
+impl BodyPart {
+    pub const COUNT: usize = 24;
+
+    pub const ALL: [BodyPart; BodyPart::COUNT] = [
+        BodyPart::LeftFace,
+        BodyPart::RightFace,
+        BodyPart::LeftUpperArmFront,
+        BodyPart::LeftUpperArmBack,
+        BodyPart::RightUpperArmFront,
+        BodyPart::RightUpperArmBack,
+        BodyPart::LeftLowerArmFront,
+        BodyPart::LeftLowerArmBack,
+        BodyPart::RightLowerArmFront,
+        BodyPart::RightLowerArmBack,
+        BodyPart::LeftHand,
+        BodyPart::RightHand,
+        BodyPart::TorsoFront,
+        BodyPart::TorsoBack,
+        BodyPart::LeftUpperLegFront,
+        BodyPart::LeftUpperLegBack,
+        BodyPart::RightUpperLegFront,
+        BodyPart::RightUpperLegBack,
+        BodyPart::LeftLowerLegFront,
+        BodyPart::LeftLowerLegBack,
+        BodyPart::RightLowerLegFront,
+        BodyPart::RightLowerLegBack,
+        BodyPart::LeftFoot,
+        BodyPart::RightFoot,
+    ];
+
+    pub fn from_id(id: usize) -> Option<BodyPart> {
+        BodyPart::ALL.get(id).copied()
+    }
+
+    pub fn id(self) -> usize {
+        self as usize
+    }
+
+    // Same names as the parts in the body-pix JS library
+    pub fn name(self) -> &'static str {
+        match self {
+            BodyPart::LeftFace => "left_face",
+            BodyPart::RightFace => "right_face",
+            BodyPart::LeftUpperArmFront => "left_upper_arm_front",
+            BodyPart::LeftUpperArmBack => "left_upper_arm_back",
+            BodyPart::RightUpperArmFront => "right_upper_arm_front",
+            BodyPart::RightUpperArmBack => "right_upper_arm_back",
+            BodyPart::LeftLowerArmFront => "left_lower_arm_front",
+            BodyPart::LeftLowerArmBack => "left_lower_arm_back",
+            BodyPart::RightLowerArmFront => "right_lower_arm_front",
+            BodyPart::RightLowerArmBack => "right_lower_arm_back",
+            BodyPart::LeftHand => "left_hand",
+            BodyPart::RightHand => "right_hand",
+            BodyPart::TorsoFront => "torso_front",
+            BodyPart::TorsoBack => "torso_back",
+            BodyPart::LeftUpperLegFront => "left_upper_leg_front",
+            BodyPart::LeftUpperLegBack => "left_upper_leg_back",
+            BodyPart::RightUpperLegFront => "right_upper_leg_front",
+            BodyPart::RightUpperLegBack => "right_upper_leg_back",
+            BodyPart::LeftLowerLegFront => "left_lower_leg_front",
+            BodyPart::LeftLowerLegBack => "left_lower_leg_back",
+            BodyPart::RightLowerLegFront => "right_lower_leg_front",
+            BodyPart::RightLowerLegBack => "right_lower_leg_back",
+            BodyPart::LeftFoot => "left_feet",
+            BodyPart::RightFoot => "right_feet",
+        }
+    }
+
+    pub fn is_face(self) -> bool {
+        matches!(self, BodyPart::LeftFace | BodyPart::RightFace)
+    }
+}
+
+pub struct PartSegments {
+    pub orig_width: usize,
+    pub orig_height: usize,
+    parts: Vec<Segments>,
+    ids: Vec<u8>, // Argmax part id for each cell of the output grid
+}
+
+impl PartSegments {
+    #[cfg(feature = "tensorflow")]
+    pub fn from_tensor(stride: u32, orig_width: usize, orig_height: usize, t: &Tensor<f32>) -> Result<PartSegments> {
+        PartSegments::from_output(stride, orig_width, orig_height, &OutputTensor::from(t))
+    }
+
+    pub fn from_output(stride: u32, orig_width: usize, orig_height: usize, t: &OutputTensor) -> Result<PartSegments> {
+        PartSegments::from_heatmaps(stride, InputTransform::identity(orig_width, orig_height), t)
+    }
+
+    pub(crate) fn from_heatmaps(stride: u32, transform: InputTransform, t: &OutputTensor) -> Result<PartSegments> {
+        if t.dims().len() != 4 || t.dims()[3] as usize != BodyPart::COUNT {
+            return Err(BodyPixError::InvalidTensor(format!(
+                "expected part heatmaps with {} channels, got shape {:?}",
+                BodyPart::COUNT,
+                t.dims()
+            )));
+        }
+
+        let mut parts = Vec::with_capacity(BodyPart::COUNT);
+        for channel in 0..BodyPart::COUNT {
+            parts.push(Segments::from_tensor_channel(stride, transform, t, channel)?);
+        }
+
+        let cells = parts[0].values.len();
+        let ids = (0..cells)
+            .map(|i| {
+                (0..BodyPart::COUNT)
+                    .max_by(|&a, &b| {
+                        parts[a].values[i]
+                            .partial_cmp(&parts[b].values[i])
+                            .unwrap_or(Ordering::Equal)
+                    })
+                    .unwrap() as u8
+            })
+            .collect();
+
+        Ok(PartSegments {
+            orig_width: transform.orig_width,
+            orig_height: transform.orig_height,
+            parts,
+            ids,
+        })
+    }
+
+    // Most likely part at a pixel in the original image, from the nearest cell
+    pub fn part(&self, x: usize, y: usize) -> BodyPart {
+        let grid = &self.parts[0];
+        let (grid_x, grid_y) = grid.grid_coords(x, y);
+        let step_x = std::cmp::min(grid_x.round() as usize, grid.width - 1);
+        let step_y = std::cmp::min(grid_y.round() as usize, grid.height - 1);
+        BodyPart::ALL[self.ids[step_x + step_y * grid.width] as usize]
+    }
+
+    // Most likely part, or None where the pixel is not part of a person
+    pub fn part_in_mask(&self, mask: &Segments, threshold: f32, x: usize, y: usize) -> Option<BodyPart> {
+        if mask.linear_mean(x, y) > threshold {
+            Some(self.part(x, y))
+        } else {
+            None
+        }
+    }
+
+    // Like part_in_mask, for a mask already sampled at the size of the original image
+    pub fn part_in(&self, mask: &Mask, threshold: f32, x: usize, y: usize) -> Option<BodyPart> {
+        if mask.get(x, y) > threshold {
+            Some(self.part(x, y))
+        } else {
+            None
+        }
+    }
+
+    pub fn probability(&self, part: BodyPart, x: usize, y: usize) -> f32 {
+        self.parts[part.id()].linear_mean(x, y)
+    }
+
+    pub fn part_segments(&self, part: BodyPart) -> &Segments {
+        &self.parts[part.id()]
+    }
+}
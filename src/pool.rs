@@ -0,0 +1,164 @@
+#[cfg(feature = "tensorflow")]
+use std::path::Path;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use image::DynamicImage;
+
+use crate::bodypix::{BodyPix, Segments};
+use crate::error::{BodyPixError, Result};
+#[cfg(feature = "tensorflow")]
+use crate::models::ModelSpec;
+#[cfg(feature = "tensorflow")]
+use crate::options::BodyPixOptions;
+
+type Job = Box<dyn FnOnce(&BodyPix) + Send>;
+
+// Runs jobs on worker threads, so callers like the UI thread don't block on
+// inference and concurrent requests don't wait for each other. Workers either
+// share one model, as a session can run several inputs at once, or each have
+// their own.
+pub struct BodyPixPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<Arc<BodyPix>>>,
+}
+
+impl BodyPixPool {
+    // Workers sharing one model and session
+    pub fn new(body_pix: BodyPix, workers: usize) -> BodyPixPool {
+        let body_pix = Arc::new(body_pix);
+        BodyPixPool::start((0..workers.max(1)).map(|_| body_pix.clone()).collect())
+    }
+
+    // A worker for each model, each with a session of its own
+    pub fn with_models(models: Vec<BodyPix>) -> BodyPixPool {
+        assert!(!models.is_empty(), "a pool needs at least one model");
+        BodyPixPool::start(models.into_iter().map(Arc::new).collect())
+    }
+
+    // Loads the model once for each worker
+    #[cfg(feature = "tensorflow")]
+    pub fn from_spec(models_dir: &Path, spec: &ModelSpec, workers: usize) -> Result<BodyPixPool> {
+        BodyPixPool::from_spec_with_options(models_dir, spec, workers, &BodyPixOptions::default())
+    }
+
+    // With several workers, limiting each session's threads keeps them from
+    // competing for the same cores
+    #[cfg(feature = "tensorflow")]
+    pub fn from_spec_with_options(
+        models_dir: &Path,
+        spec: &ModelSpec,
+        workers: usize,
+        options: &BodyPixOptions,
+    ) -> Result<BodyPixPool> {
+        let models = (0..workers.max(1))
+            .map(|_| BodyPix::from_spec_with_options(models_dir, spec, options))
+            .collect::<Result<_>>()?;
+        Ok(BodyPixPool::with_models(models))
+    }
+
+    fn start(models: Vec<Arc<BodyPix>>) -> BodyPixPool {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = models
+            .into_iter()
+            .map(|body_pix| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || {
+                    loop {
+                        // The lock is only held while waiting, not while running the job
+                        let job = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        match job {
+                            Ok(job) => job(&body_pix),
+                            Err(_) => break, // The pool was dropped
+                        }
+                    }
+                    body_pix
+                })
+            })
+            .collect();
+
+        BodyPixPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    // Queues a job for the next free worker
+    pub fn submit<T, F>(&self, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&BodyPix) -> Result<T> + Send + 'static,
+    {
+        let (result_sender, receiver) = sync_channel(1);
+        let job: Job = Box::new(move |body_pix| {
+            // The handle may have been dropped, and then nobody wants the result
+            let _ = result_sender.send(job(body_pix));
+        });
+
+        // If the workers are gone, the job is dropped, and the handle reports it
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(job);
+        }
+        JobHandle { receiver }
+    }
+
+    pub fn process_image(&self, image: DynamicImage) -> JobHandle<Segments> {
+        self.submit(move |body_pix| body_pix.process_image(&image))
+    }
+
+    pub fn process_batch(&self, images: Vec<DynamicImage>) -> JobHandle<Vec<Segments>> {
+        self.submit(move |body_pix| body_pix.process_batch(&images))
+    }
+
+    // Finishes the queued jobs, stops the workers and hands back the models
+    pub fn into_models(mut self) -> Vec<BodyPix> {
+        // A shared model only unwraps once the other workers' references
+        // are dropped, so it comes back once
+        self.shut_down()
+            .into_iter()
+            .filter_map(|body_pix| Arc::try_unwrap(body_pix).ok())
+            .collect()
+    }
+
+    fn shut_down(&mut self) -> Vec<Arc<BodyPix>> {
+        self.sender = None;
+        self.workers.drain(..).filter_map(|worker| worker.join().ok()).collect()
+    }
+}
+
+impl Drop for BodyPixPool {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+// The result of a job, once a worker has run it
+pub struct JobHandle<T> {
+    receiver: Receiver<Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    // Blocks until the job is done
+    pub fn wait(self) -> Result<T> {
+        self.receiver.recv().unwrap_or(Err(BodyPixError::WorkerStopped))
+    }
+
+    // The result if the job is done, without blocking
+    pub fn try_get(&self) -> Option<Result<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(BodyPixError::WorkerStopped)),
+        }
+    }
+}
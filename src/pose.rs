@@ -0,0 +1,387 @@
+use std::cmp::Ordering;
+
+use crate::backend::OutputTensor;
+use crate::bodypix::Segments;
+use crate::error::{BodyPixError, Result};
+use crate::preprocessing::InputTransform;
+
+// COCO keypoints, in the channel order of the "float_heatmaps" output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeypointType {
+    Nose = 0,
+    LeftEye,
+    RightEye,
+    LeftEar,
+    RightEar,
+    LeftShoulder,
+    RightShoulder,
+    LeftElbow,
+    RightElbow,
+    LeftWrist,
+    RightWrist,
+    LeftHip,
+    RightHip,
+    LeftKnee,
+    RightKnee,
+    LeftAnkle,
+    RightAnkle,
+}
+
+impl KeypointType {
+    pub const COUNT: usize = 17;
+
+    pub const ALL: [KeypointType; KeypointType::COUNT] = [
+        KeypointType::Nose,
+        KeypointType::LeftEye,
+        KeypointType::RightEye,
+        KeypointType::LeftEar,
+        KeypointType::RightEar,
+        KeypointType::LeftShoulder,
+        KeypointType::RightShoulder,
+        KeypointType::LeftElbow,
+        KeypointType::RightElbow,
+        KeypointType::LeftWrist,
+        KeypointType::RightWrist,
+        KeypointType::LeftHip,
+        KeypointType::RightHip,
+        KeypointType::LeftKnee,
+        KeypointType::RightKnee,
+        KeypointType::LeftAnkle,
+        KeypointType::RightAnkle,
+    ];
+
+    pub fn from_id(id: usize) -> Option<KeypointType> {
+        KeypointType::ALL.get(id).copied()
+    }
+
+    pub fn id(self) -> usize {
+        self as usize
+    }
+
+    // Same names as the keypoints in the body-pix JS library
+    pub fn name(self) -> &'static str {
+        match self {
+            KeypointType::Nose => "nose",
+            KeypointType::LeftEye => "leftEye",
+            KeypointType::RightEye => "rightEye",
+            KeypointType::LeftEar => "leftEar",
+            KeypointType::RightEar => "rightEar",
+            KeypointType::LeftShoulder => "leftShoulder",
+            KeypointType::RightShoulder => "rightShoulder",
+            KeypointType::LeftElbow => "leftElbow",
+            KeypointType::RightElbow => "rightElbow",
+            KeypointType::LeftWrist => "leftWrist",
+            KeypointType::RightWrist => "rightWrist",
+            KeypointType::LeftHip => "leftHip",
+            KeypointType::RightHip => "rightHip",
+            KeypointType::LeftKnee => "leftKnee",
+            KeypointType::RightKnee => "rightKnee",
+            KeypointType::LeftAnkle => "leftAnkle",
+            KeypointType::RightAnkle => "rightAnkle",
+        }
+    }
+}
+
+// Parent -> child edges of the skeleton. The displacement outputs have one
+// channel pair per edge, in this order.
+pub const POSE_CHAIN: [(KeypointType, KeypointType); 16] = [
+    (KeypointType::Nose, KeypointType::LeftEye),
+    (KeypointType::LeftEye, KeypointType::LeftEar),
+    (KeypointType::Nose, KeypointType::RightEye),
+    (KeypointType::RightEye, KeypointType::RightEar),
+    (KeypointType::Nose, KeypointType::LeftShoulder),
+    (KeypointType::LeftShoulder, KeypointType::LeftElbow),
+    (KeypointType::LeftElbow, KeypointType::LeftWrist),
+    (KeypointType::LeftShoulder, KeypointType::LeftHip),
+    (KeypointType::LeftHip, KeypointType::LeftKnee),
+    (KeypointType::LeftKnee, KeypointType::LeftAnkle),
+    (KeypointType::Nose, KeypointType::RightShoulder),
+    (KeypointType::RightShoulder, KeypointType::RightElbow),
+    (KeypointType::RightElbow, KeypointType::RightWrist),
+    (KeypointType::RightShoulder, KeypointType::RightHip),
+    (KeypointType::RightHip, KeypointType::RightKnee),
+    (KeypointType::RightKnee, KeypointType::RightAnkle),
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keypoint {
+    pub part: KeypointType,
+    pub x: f32, // Position in the original image
+    pub y: f32,
+    pub score: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pose {
+    pub keypoints: Vec<Keypoint>, // Indexed by KeypointType::id()
+    pub score: f32,
+}
+
+impl Pose {
+    pub fn keypoint(&self, part: KeypointType) -> &Keypoint {
+        &self.keypoints[part.id()]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MultiPoseConfig {
+    pub max_detections: usize,
+    pub score_threshold: f32,
+    pub nms_radius: f32, // In pixels of the network input, which is scaled from the original image
+}
+
+impl Default for MultiPoseConfig {
+    fn default() -> Self {
+        MultiPoseConfig {
+            max_detections: 10,
+            score_threshold: 0.5,
+            nms_radius: 20.,
+        }
+    }
+}
+
+// A [1, height, width, channels] output, copied out of its tensor
+pub(crate) struct Grid {
+    width: usize,
+    height: usize,
+    channels: usize,
+    values: Vec<f32>,
+}
+
+impl Grid {
+    pub(crate) fn from_tensor(t: &OutputTensor, channels: usize) -> Result<Grid> {
+        let dims = t.dims();
+        if dims.len() != 4 || dims[3] as usize != channels {
+            return Err(BodyPixError::InvalidTensor(format!(
+                "expected {} channels in tensor of rank 4, got shape {:?}",
+                channels, dims
+            )));
+        }
+
+        Ok(Grid {
+            width: dims[2] as usize,
+            height: dims[1] as usize,
+            channels,
+            values: t.to_vec(),
+        })
+    }
+
+    pub(crate) fn get(&self, y: usize, x: usize, channel: usize) -> f32 {
+        self.values[(y * self.width + x) * self.channels + channel]
+    }
+
+    pub(crate) fn nearest_cell(&self, stride: u32, x: f32, y: f32) -> (usize, usize) {
+        let stride = stride as f32;
+        let cell_x = (x / stride).round().max(0.).min((self.width - 1) as f32);
+        let cell_y = (y / stride).round().max(0.).min((self.height - 1) as f32);
+        (cell_x as usize, cell_y as usize)
+    }
+
+    // Offset and displacement grids store all y components before all x components
+    pub(crate) fn vector(&self, y: usize, x: usize, id: usize) -> (f32, f32) {
+        let half = self.channels / 2;
+        (self.get(y, x, half + id), self.get(y, x, id))
+    }
+}
+
+// The model outputs needed to decode poses. Poses are decoded in the
+// coordinates of the network input, and mapped back to the image afterwards.
+pub(crate) struct PoseOutputs {
+    stride: u32,
+    bounds: (f32, f32, f32, f32), // Of the image in the input, as (min_x, min_y, max_x, max_y)
+    scores: Grid,
+    offsets: Grid,
+    displacements_fwd: Grid,
+    displacements_bwd: Grid,
+}
+
+impl PoseOutputs {
+    pub(crate) fn from_tensors(
+        stride: u32,
+        transform: &InputTransform,
+        heatmaps: &OutputTensor,
+        offsets: &OutputTensor,
+        displacements_fwd: &OutputTensor,
+        displacements_bwd: &OutputTensor,
+    ) -> Result<PoseOutputs> {
+        let mut scores = Grid::from_tensor(heatmaps, KeypointType::COUNT)?;
+        for value in scores.values.iter_mut() {
+            *value = Segments::sigmoid(*value);
+        }
+
+        Ok(PoseOutputs {
+            stride,
+            bounds: transform.input_bounds(),
+            scores,
+            offsets: Grid::from_tensor(offsets, 2 * KeypointType::COUNT)?,
+            displacements_fwd: Grid::from_tensor(displacements_fwd, 2 * POSE_CHAIN.len())?,
+            displacements_bwd: Grid::from_tensor(displacements_bwd, 2 * POSE_CHAIN.len())?,
+        })
+    }
+
+    // Image position of a keypoint found in a given cell
+    fn image_coords(&self, y: usize, x: usize, part: usize) -> (f32, f32) {
+        let (offset_x, offset_y) = self.offsets.vector(y, x, part);
+        (
+            x as f32 * self.stride as f32 + offset_x,
+            y as f32 * self.stride as f32 + offset_y,
+        )
+    }
+
+    fn nearest_cell(&self, x: f32, y: f32) -> (usize, usize) {
+        self.scores.nearest_cell(self.stride, x, y)
+    }
+
+    fn keypoint(&self, part: usize, x: f32, y: f32, score: f32) -> Keypoint {
+        Keypoint {
+            part: KeypointType::ALL[part],
+            x: x.max(self.bounds.0).min(self.bounds.2),
+            y: y.max(self.bounds.1).min(self.bounds.3),
+            score,
+        }
+    }
+
+    // Follows a displacement edge from a known keypoint, then refines the
+    // position with the short-range offsets of the target keypoint
+    fn traverse(&self, edge: usize, source: &Keypoint, target: usize, displacements: &Grid) -> Keypoint {
+        let (cell_x, cell_y) = self.nearest_cell(source.x, source.y);
+        let (disp_x, disp_y) = displacements.vector(cell_y, cell_x, edge);
+        let mut x = source.x + disp_x;
+        let mut y = source.y + disp_y;
+
+        for _ in 0..2 {
+            let (cell_x, cell_y) = self.nearest_cell(x, y);
+            let (target_x, target_y) = self.image_coords(cell_y, cell_x, target);
+            x = target_x;
+            y = target_y;
+        }
+
+        let (cell_x, cell_y) = self.nearest_cell(x, y);
+        let score = self.scores.get(cell_y, cell_x, target);
+        self.keypoint(target, x, y, score)
+    }
+
+    fn decode_pose(&self, root_part: usize, root_x: f32, root_y: f32, root_score: f32) -> Vec<Keypoint> {
+        let mut keypoints: Vec<Option<Keypoint>> = vec![None; KeypointType::COUNT];
+        keypoints[root_part] = Some(self.keypoint(root_part, root_x, root_y, root_score));
+
+        // Up the tree, along the backward displacements
+        for (edge, (parent, child)) in POSE_CHAIN.iter().enumerate().rev() {
+            if let (Some(source), None) = (keypoints[child.id()], keypoints[parent.id()]) {
+                keypoints[parent.id()] = Some(self.traverse(edge, &source, parent.id(), &self.displacements_bwd));
+            }
+        }
+
+        // Down the tree, along the forward displacements
+        for (edge, (parent, child)) in POSE_CHAIN.iter().enumerate() {
+            if let (Some(source), None) = (keypoints[parent.id()], keypoints[child.id()]) {
+                keypoints[child.id()] = Some(self.traverse(edge, &source, child.id(), &self.displacements_fwd));
+            }
+        }
+
+        keypoints.into_iter().map(|keypoint| keypoint.unwrap()).collect()
+    }
+}
+
+pub(crate) fn decode_single_pose(outputs: &PoseOutputs) -> Pose {
+    let scores = &outputs.scores;
+    let mut keypoints = Vec::with_capacity(KeypointType::COUNT);
+    let mut total_score = 0.;
+
+    for part in 0..KeypointType::COUNT {
+        let mut best = (0, 0, f32::MIN);
+        for y in 0..scores.height {
+            for x in 0..scores.width {
+                let score = scores.get(y, x, part);
+                if score > best.2 {
+                    best = (x, y, score);
+                }
+            }
+        }
+
+        let (x, y) = outputs.image_coords(best.1, best.0, part);
+        keypoints.push(outputs.keypoint(part, x, y, best.2));
+        total_score += best.2;
+    }
+
+    Pose {
+        keypoints,
+        score: total_score / KeypointType::COUNT as f32,
+    }
+}
+
+fn squared_distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0) * (a.0 - b.0) + (a.1 - b.1) * (a.1 - b.1)
+}
+
+fn within_nms_radius(poses: &[Pose], squared_nms_radius: f32, x: f32, y: f32, part: usize) -> bool {
+    poses.iter().any(|pose| {
+        let keypoint = &pose.keypoints[part];
+        squared_distance((keypoint.x, keypoint.y), (x, y)) <= squared_nms_radius
+    })
+}
+
+// Candidate roots: cells that are a local maximum of a keypoint heatmap
+fn local_maxima(scores: &Grid, score_threshold: f32) -> Vec<(usize, usize, usize, f32)> {
+    const LOCAL_MAXIMUM_RADIUS: usize = 1;
+
+    let mut candidates = Vec::new();
+    for y in 0..scores.height {
+        for x in 0..scores.width {
+            for part in 0..KeypointType::COUNT {
+                let score = scores.get(y, x, part);
+                if score < score_threshold {
+                    continue;
+                }
+
+                let y_start = y.saturating_sub(LOCAL_MAXIMUM_RADIUS);
+                let y_end = std::cmp::min(y + LOCAL_MAXIMUM_RADIUS + 1, scores.height);
+                let x_start = x.saturating_sub(LOCAL_MAXIMUM_RADIUS);
+                let x_end = std::cmp::min(x + LOCAL_MAXIMUM_RADIUS + 1, scores.width);
+                let is_maximum = (y_start..y_end)
+                    .all(|ny| (x_start..x_end).all(|nx| scores.get(ny, nx, part) <= score));
+
+                if is_maximum {
+                    candidates.push((x, y, part, score));
+                }
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| b.3.partial_cmp(&a.3).unwrap_or(Ordering::Equal));
+    candidates
+}
+
+pub(crate) fn decode_multiple_poses(outputs: &PoseOutputs, config: &MultiPoseConfig) -> Vec<Pose> {
+    let squared_nms_radius = config.nms_radius * config.nms_radius;
+    let mut poses: Vec<Pose> = Vec::new();
+
+    for (x, y, part, score) in local_maxima(&outputs.scores, config.score_threshold) {
+        if poses.len() >= config.max_detections {
+            break;
+        }
+
+        let (root_x, root_y) = outputs.image_coords(y, x, part);
+        if within_nms_radius(&poses, squared_nms_radius, root_x, root_y, part) {
+            continue;
+        }
+
+        let keypoints = outputs.decode_pose(part, root_x, root_y, score);
+
+        // Keypoints that overlap an earlier pose don't count towards the score
+        let total_score: f32 = keypoints
+            .iter()
+            .filter(|keypoint| {
+                !within_nms_radius(&poses, squared_nms_radius, keypoint.x, keypoint.y, keypoint.part.id())
+            })
+            .map(|keypoint| keypoint.score)
+            .sum();
+
+        poses.push(Pose {
+            keypoints,
+            score: total_score / KeypointType::COUNT as f32,
+        });
+    }
+
+    poses
+}
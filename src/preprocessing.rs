@@ -0,0 +1,341 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::error::{BodyPixError, Result};
+use crate::models::ModelType;
+use crate::pose::Pose;
+
+// How large the image is when it goes into the network. The time an inference
+// takes grows with the number of pixels, so a smaller resolution is much
+// faster, at the cost of detail in the mask.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InternalResolution {
+    Full,
+    High,   // 0.75 of the original size
+    Medium, // 0.5
+    Low,    // 0.25
+    Scale(f32),
+    // Fits the image inside this size, keeping the aspect ratio and padding the
+    // rest, so every image gives the network the same input shape
+    Size { width: u32, height: u32 },
+}
+
+impl InternalResolution {
+    // The size of the resized image, and of the area it is padded out to
+    fn sizes(self, orig_width: u32, orig_height: u32) -> (u32, u32, u32, u32) {
+        let scale = match self {
+            InternalResolution::Full => 1.,
+            InternalResolution::High => 0.75,
+            InternalResolution::Medium => 0.5,
+            InternalResolution::Low => 0.25,
+            InternalResolution::Scale(scale) => scale,
+            InternalResolution::Size { width, height } => {
+                let (width, height) = (width.max(1), height.max(1));
+                let scale = (width as f32 / orig_width as f32).min(height as f32 / orig_height as f32);
+                let resized_width = ((orig_width as f32 * scale).round() as u32).min(width).max(1);
+                let resized_height = ((orig_height as f32 * scale).round() as u32).min(height).max(1);
+                return (resized_width, resized_height, width, height);
+            }
+        };
+
+        let width = ((orig_width as f32 * scale).round() as u32).max(1);
+        let height = ((orig_height as f32 * scale).round() as u32).max(1);
+        (width, height, width, height)
+    }
+}
+
+// Parses the names the JS library uses ("low", "medium", "high", "full"), a
+// scale like "0.6", or a size like "640x480"
+impl FromStr for InternalResolution {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "full" => return Ok(InternalResolution::Full),
+            "high" => return Ok(InternalResolution::High),
+            "medium" => return Ok(InternalResolution::Medium),
+            "low" => return Ok(InternalResolution::Low),
+            _ => {}
+        }
+
+        let invalid = || format!("expected full, high, medium, low, a scale or WIDTHxHEIGHT, got \"{}\"", value);
+        let mut size = value.split('x');
+        match (size.next(), size.next(), size.next()) {
+            (Some(width), Some(height), None) => Ok(InternalResolution::Size {
+                width: width.parse().map_err(|_| invalid())?,
+                height: height.parse().map_err(|_| invalid())?,
+            }),
+            _ => match value.parse::<f32>() {
+                Ok(scale) if scale > 0. => Ok(InternalResolution::Scale(scale)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputConfig {
+    pub internal_resolution: InternalResolution,
+    pub filter: FilterType, // For resizing to the internal resolution
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig {
+            internal_resolution: InternalResolution::Full,
+            filter: FilterType::Triangle,
+        }
+    }
+}
+
+// Turns 8-bit RGB into the values a model was trained on
+pub trait Normalization: Send + Sync {
+    fn normalize(&self, rgb: [u8; 3]) -> [f32; 3];
+
+    // A row of RGB8 pixels at a time, which the built-in normalizations
+    // override with plain loops the compiler can vectorize
+    fn normalize_row(&self, rgb: &[u8], values: &mut [f32]) {
+        for (pixel, value) in rgb.chunks_exact(3).zip(values.chunks_exact_mut(3)) {
+            value.copy_from_slice(&self.normalize([pixel[0], pixel[1], pixel[2]]));
+        }
+    }
+}
+
+// Scales each channel to [-1, 1]
+pub struct MobileNetNormalization;
+
+impl Normalization for MobileNetNormalization {
+    fn normalize(&self, rgb: [u8; 3]) -> [f32; 3] {
+        [
+            rgb[0] as f32 / 127.5 - 1.,
+            rgb[1] as f32 / 127.5 - 1.,
+            rgb[2] as f32 / 127.5 - 1.,
+        ]
+    }
+
+    fn normalize_row(&self, rgb: &[u8], values: &mut [f32]) {
+        for (channel, value) in rgb.iter().zip(values.iter_mut()) {
+            *value = *channel as f32 / 127.5 - 1.;
+        }
+    }
+}
+
+// Subtracts the mean of each channel in the training images
+pub struct ResNetNormalization;
+
+impl Normalization for ResNetNormalization {
+    fn normalize(&self, rgb: [u8; 3]) -> [f32; 3] {
+        [
+            rgb[0] as f32 - 123.15,
+            rgb[1] as f32 - 115.9,
+            rgb[2] as f32 - 103.06,
+        ]
+    }
+
+    fn normalize_row(&self, rgb: &[u8], values: &mut [f32]) {
+        const MEAN: [f32; 3] = [123.15, 115.9, 103.06];
+        for (pixel, value) in rgb.chunks_exact(3).zip(values.chunks_exact_mut(3)) {
+            value[0] = pixel[0] as f32 - MEAN[0];
+            value[1] = pixel[1] as f32 - MEAN[1];
+            value[2] = pixel[2] as f32 - MEAN[2];
+        }
+    }
+}
+
+pub(crate) fn default_normalization(model_type: ModelType) -> Box<dyn Normalization> {
+    match model_type {
+        ModelType::MobileNet => Box::new(MobileNetNormalization),
+        ModelType::ResNet => Box::new(ResNetNormalization),
+    }
+}
+
+// Where the original image ended up in the network input, to map the outputs
+// back. Positions are of pixel centres, so they hold for any scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct InputTransform {
+    pub(crate) orig_width: usize,
+    pub(crate) orig_height: usize,
+    pub(crate) width: usize, // Of the resized image, without padding
+    pub(crate) height: usize,
+    pub(crate) pad_x: usize,
+    pub(crate) pad_y: usize,
+    pub(crate) input_width: usize, // With padding
+    pub(crate) input_height: usize,
+}
+
+impl InputTransform {
+    pub(crate) fn identity(width: usize, height: usize) -> InputTransform {
+        InputTransform {
+            orig_width: width,
+            orig_height: height,
+            width,
+            height,
+            pad_x: 0,
+            pad_y: 0,
+            input_width: width,
+            input_height: height,
+        }
+    }
+
+    fn scale(&self) -> (f32, f32) {
+        (
+            self.width as f32 / self.orig_width as f32,
+            self.height as f32 / self.orig_height as f32,
+        )
+    }
+
+    pub(crate) fn to_input(self, x: f32, y: f32) -> (f32, f32) {
+        let (scale_x, scale_y) = self.scale();
+        (
+            (x + 0.5) * scale_x - 0.5 + self.pad_x as f32,
+            (y + 0.5) * scale_y - 0.5 + self.pad_y as f32,
+        )
+    }
+
+    pub(crate) fn to_original(self, x: f32, y: f32) -> (f32, f32) {
+        let (scale_x, scale_y) = self.scale();
+        (
+            (x - self.pad_x as f32 + 0.5) / scale_x - 0.5,
+            (y - self.pad_y as f32 + 0.5) / scale_y - 0.5,
+        )
+    }
+
+    // The resized image in the input, as (min_x, min_y, max_x, max_y)
+    pub(crate) fn input_bounds(&self) -> (f32, f32, f32, f32) {
+        (
+            self.pad_x as f32,
+            self.pad_y as f32,
+            (self.pad_x + self.width - 1) as f32,
+            (self.pad_y + self.height - 1) as f32,
+        )
+    }
+
+    pub(crate) fn pose_to_original(&self, mut pose: Pose) -> Pose {
+        let max_x = (self.orig_width - 1) as f32;
+        let max_y = (self.orig_height - 1) as f32;
+        for keypoint in pose.keypoints.iter_mut() {
+            let (x, y) = self.to_original(keypoint.x, keypoint.y);
+            keypoint.x = x.max(0.).min(max_x);
+            keypoint.y = y.max(0.).min(max_y);
+        }
+        pose
+    }
+}
+
+// Resizes, pads and normalizes an image for the network, into values laid out
+// as [height, width, 3]. The vector is reused, so preparing many images doesn't
+// allocate for each one. Returns the width and height of the input.
+pub fn prepare_input(
+    image: &DynamicImage,
+    config: &InputConfig,
+    normalization: &dyn Normalization,
+    values: &mut Vec<f32>,
+) -> Result<(usize, usize)> {
+    let input_size = input_size(image, config)?;
+    let (width, height) = input_dims(input_size);
+    values.resize(width * height * 3, 0.);
+    fill_input(image, config, normalization, input_size, values)?;
+    Ok((width, height))
+}
+
+// The size of the area an image is padded out to, before the extra pixel
+pub(crate) fn input_size(image: &DynamicImage, config: &InputConfig) -> Result<(u32, u32)> {
+    let (orig_width, orig_height) = image.dimensions();
+    if orig_width == 0 || orig_height == 0 {
+        return Err(BodyPixError::UnsupportedImage(format!(
+            "image has no pixels ({}x{})",
+            orig_width, orig_height
+        )));
+    }
+
+    let (_, _, input_width, input_height) = config.internal_resolution.sizes(orig_width, orig_height);
+    Ok((input_width, input_height))
+}
+
+// Nudge target width/height one step above stride (improves quality!)
+pub(crate) fn input_dims((input_width, input_height): (u32, u32)) -> (usize, usize) {
+    (input_width as usize + 1, input_height as usize + 1)
+}
+
+// Writes an image into values of input_dims(input_size), which can be the
+// memory of a tensor. The input size must be at least the image's own, and is
+// larger when images of different sizes share a batch.
+pub(crate) fn fill_input(
+    image: &DynamicImage,
+    config: &InputConfig,
+    normalization: &dyn Normalization,
+    input_size: (u32, u32),
+    values: &mut [f32],
+) -> Result<InputTransform> {
+    let (orig_width, orig_height) = image.dimensions();
+    if orig_width == 0 || orig_height == 0 {
+        return Err(BodyPixError::UnsupportedImage(format!(
+            "image has no pixels ({}x{})",
+            orig_width, orig_height
+        )));
+    }
+
+    let (input_width, input_height) = input_size;
+    let (target_width, target_height) = input_dims(input_size);
+    if values.len() != target_width * target_height * 3 {
+        return Err(BodyPixError::InvalidTensor(format!(
+            "expected {} input values for {}x{}, got {}",
+            target_width * target_height * 3,
+            target_width,
+            target_height,
+            values.len()
+        )));
+    }
+
+    let (width, height, _, _) = config.internal_resolution.sizes(orig_width, orig_height);
+    let (width, height) = (width.min(input_width), height.min(input_height));
+    // RGB8 images at their own size, like most decoded photos, are read in place
+    let resized = match image {
+        DynamicImage::ImageRgb8(rgb) if (width, height) == (orig_width, orig_height) => Cow::Borrowed(rgb),
+        _ if (width, height) == (orig_width, orig_height) => Cow::Owned(image.to_rgb8()),
+        _ => Cow::Owned(image.resize_exact(width, height, config.filter).to_rgb8()),
+    };
+    let pad_x = ((input_width - width) / 2) as usize;
+    let pad_y = ((input_height - height) / 2) as usize;
+    let (width, height) = (width as usize, height as usize);
+
+    // The padding, and the extra pixel, repeat the edges of the image
+    let pixels: &[u8] = resized.as_raw();
+    let fill_row = |(y, row): (usize, &mut [f32])| {
+        let source_y = y.saturating_sub(pad_y).min(height - 1);
+        let source = &pixels[source_y * width * 3..(source_y + 1) * width * 3];
+        let (left, rest) = row.split_at_mut(pad_x * 3);
+        let (middle, right) = rest.split_at_mut(width * 3);
+
+        normalization.normalize_row(source, middle);
+        let first = [middle[0], middle[1], middle[2]];
+        let last = [middle[width * 3 - 3], middle[width * 3 - 2], middle[width * 3 - 1]];
+        for pixel in left.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&first);
+        }
+        for pixel in right.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&last);
+        }
+    };
+
+    #[cfg(feature = "parallel")]
+    values.par_chunks_mut(target_width * 3).enumerate().for_each(fill_row);
+    #[cfg(not(feature = "parallel"))]
+    values.chunks_mut(target_width * 3).enumerate().for_each(fill_row);
+
+    Ok(InputTransform {
+        orig_width: orig_width as usize,
+        orig_height: orig_height as usize,
+        width,
+        height,
+        pad_x,
+        pad_y,
+        input_width: input_width as usize,
+        input_height: input_height as usize,
+    })
+}
@@ -0,0 +1,316 @@
+use crate::sampling::Mask;
+
+// Cleans up a full-resolution mask: thresholds it, erodes and dilates it,
+// removes small islands of foreground, fills small holes, and feathers the
+// edges. Each step is left out until it is configured, so the default only
+// thresholds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaskRefiner {
+    threshold: f32,
+    erode: usize, // Radius in pixels
+    dilate: usize,
+    min_island_area: usize,
+    max_hole_area: usize,
+    feather: f32, // Gaussian sigma in pixels
+}
+
+impl Default for MaskRefiner {
+    fn default() -> Self {
+        MaskRefiner {
+            threshold: 0.7,
+            erode: 0,
+            dilate: 0,
+            min_island_area: 0,
+            max_hole_area: 0,
+            feather: 0.,
+        }
+    }
+}
+
+impl MaskRefiner {
+    pub fn new() -> MaskRefiner {
+        MaskRefiner::default()
+    }
+
+    // Pixels above this probability are foreground
+    pub fn with_threshold(mut self, threshold: f32) -> MaskRefiner {
+        self.threshold = threshold;
+        self
+    }
+
+    // Shrinks the foreground by a square of this radius, which cuts off thin
+    // false positives. Erosion runs before dilation, so the two together open
+    // the mask.
+    pub fn with_erosion(mut self, radius: usize) -> MaskRefiner {
+        self.erode = radius;
+        self
+    }
+
+    // Grows the foreground by a square of this radius
+    pub fn with_dilation(mut self, radius: usize) -> MaskRefiner {
+        self.dilate = radius;
+        self
+    }
+
+    // Foreground areas smaller than this many pixels are dropped
+    pub fn with_min_island_area(mut self, area: usize) -> MaskRefiner {
+        self.min_island_area = area;
+        self
+    }
+
+    // Background areas enclosed by foreground, up to this many pixels, are
+    // filled. Large holes are kept, like the gap between an arm and the body.
+    pub fn with_max_hole_area(mut self, area: usize) -> MaskRefiner {
+        self.max_hole_area = area;
+        self
+    }
+
+    // Softens the edges with a Gaussian blur of this sigma
+    pub fn with_feather(mut self, sigma: f32) -> MaskRefiner {
+        self.feather = sigma;
+        self
+    }
+
+    pub fn refine(&self, mask: &Mask) -> Mask {
+        let mut foreground: Vec<bool> = mask.values.iter().map(|&value| value > self.threshold).collect();
+        let (width, height) = (mask.width, mask.height);
+
+        if self.erode > 0 {
+            foreground = morphology(&foreground, width, height, self.erode, false);
+        }
+        if self.dilate > 0 {
+            foreground = morphology(&foreground, width, height, self.dilate, true);
+        }
+        if self.min_island_area > 0 {
+            remove_components(&mut foreground, width, height, true, |component| {
+                component.area < self.min_island_area
+            });
+        }
+        if self.max_hole_area > 0 {
+            remove_components(&mut foreground, width, height, false, |component| {
+                !component.touches_border && component.area <= self.max_hole_area
+            });
+        }
+
+        let mut refined = Mask {
+            width,
+            height,
+            values: foreground
+                .iter()
+                .map(|&is_foreground| if is_foreground { 1. } else { 0. })
+                .collect(),
+        };
+        if self.feather > 0. {
+            gaussian_blur(&mut refined, self.feather);
+        }
+        refined
+    }
+}
+
+// Erosion is a minimum over the square around each pixel, and dilation a
+// maximum. The square is separable, so rows and columns are done in turn.
+pub(crate) fn morphology(mask: &[bool], width: usize, height: usize, radius: usize, dilate: bool) -> Vec<bool> {
+    let mut rows = vec![false; mask.len()];
+    for y in 0..height {
+        let row = &mask[y * width..(y + 1) * width];
+        for x in 0..width {
+            let window = &row[x.saturating_sub(radius)..(x + radius + 1).min(width)];
+            rows[x + y * width] = if dilate {
+                window.iter().any(|&value| value)
+            } else {
+                window.iter().all(|&value| value)
+            };
+        }
+    }
+
+    let mut result = vec![false; mask.len()];
+    for x in 0..width {
+        for y in 0..height {
+            let mut window = (y.saturating_sub(radius)..(y + radius + 1).min(height)).map(|y| rows[x + y * width]);
+            result[x + y * width] = if dilate {
+                window.any(|value| value)
+            } else {
+                window.all(|value| value)
+            };
+        }
+    }
+    result
+}
+
+struct Component {
+    area: usize,
+    touches_border: bool,
+}
+
+// Flips the 4-connected areas of pixels equal to value that remove picks
+fn remove_components(mask: &mut [bool], width: usize, height: usize, value: bool, remove: impl Fn(&Component) -> bool) {
+    let mut visited = vec![false; mask.len()];
+    let mut stack = Vec::new();
+    let mut pixels = Vec::new();
+
+    for start in 0..mask.len() {
+        if visited[start] || mask[start] != value {
+            continue;
+        }
+
+        // Flood fill from the first pixel of the component
+        pixels.clear();
+        let mut component = Component {
+            area: 0,
+            touches_border: false,
+        };
+        visited[start] = true;
+        stack.push(start);
+        while let Some(i) = stack.pop() {
+            pixels.push(i);
+            let (x, y) = (i % width, i / width);
+            component.area += 1;
+            component.touches_border |= x == 0 || y == 0 || x == width - 1 || y == height - 1;
+
+            let mut visit = |neighbour: usize| {
+                if !visited[neighbour] && mask[neighbour] == value {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            };
+            if x > 0 {
+                visit(i - 1);
+            }
+            if x + 1 < width {
+                visit(i + 1);
+            }
+            if y > 0 {
+                visit(i - width);
+            }
+            if y + 1 < height {
+                visit(i + width);
+            }
+        }
+
+        if remove(&component) {
+            for &i in &pixels {
+                mask[i] = !value;
+            }
+        }
+    }
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.).ceil() as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|offset| (-(offset * offset) as f32 / (2. * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|weight| weight / sum).collect()
+}
+
+// Separable, repeating the edge pixels past the border
+pub(crate) fn gaussian_blur(mask: &mut Mask, sigma: f32) {
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as isize;
+    let (width, height) = (mask.width as isize, mask.height as isize);
+
+    let mut rows = vec![0.; mask.values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[(x + y * width) as usize] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    let sample_x = (x + k as isize - radius).max(0).min(width - 1);
+                    mask.values[(sample_x + y * width) as usize] * weight
+                })
+                .sum();
+        }
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            mask.values[(x + y * width) as usize] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    let sample_y = (y + k as isize - radius).max(0).min(height - 1);
+                    rows[(x + sample_y * width) as usize] * weight
+                })
+                .sum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // '#' is foreground
+    fn grid(rows: &[&str]) -> Mask {
+        let width = rows[0].len();
+        let values = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|c| if c == '#' { 1. } else { 0. }))
+            .collect();
+        Mask {
+            width,
+            height: rows.len(),
+            values,
+        }
+    }
+
+    fn foreground(mask: &Mask) -> Vec<bool> {
+        mask.values.iter().map(|&value| value > 0.5).collect()
+    }
+
+    #[test]
+    fn thresholds() {
+        let mask = Mask {
+            width: 3,
+            height: 1,
+            values: vec![0.6, 0.7, 0.8],
+        };
+        assert_eq!(MaskRefiner::new().refine(&mask).values, [0., 0., 1.]);
+        assert_eq!(MaskRefiner::new().with_threshold(0.5).refine(&mask).values, [1., 1., 1.]);
+    }
+
+    #[test]
+    fn erodes_and_dilates_by_the_radius() {
+        let square = grid(&[".....", ".###.", ".###.", ".###.", "....."]);
+        let centre = grid(&[".....", ".....", "..#..", ".....", "....."]);
+        assert_eq!(morphology(&foreground(&square), 5, 5, 1, false), foreground(&centre));
+        assert_eq!(morphology(&foreground(&centre), 5, 5, 1, true), foreground(&square));
+
+        // Opening drops the thin line and keeps the square
+        let mask = grid(&["#....", ".###.", ".###.", ".###.", "....#"]);
+        let opened = MaskRefiner::new().with_erosion(1).with_dilation(1).refine(&mask);
+        assert_eq!(opened, square);
+    }
+
+    #[test]
+    fn removes_small_islands() {
+        let mask = grid(&["##...", "....#", "...##", "...##"]);
+        let refined = MaskRefiner::new().with_min_island_area(3).refine(&mask);
+        assert_eq!(refined, grid(&[".....", "....#", "...##", "...##"]));
+    }
+
+    #[test]
+    fn fills_small_holes_away_from_the_border() {
+        let mask = grid(&["####.", "#.###", "#####", "#..##", "#####"]);
+        // The background at the top right is just as small, but touches the border
+        let refined = MaskRefiner::new().with_max_hole_area(2).refine(&mask);
+        assert_eq!(refined, grid(&["####.", "#####", "#####", "#####", "#####"]));
+
+        let refined = MaskRefiner::new().with_max_hole_area(1).refine(&mask);
+        assert_eq!(refined, grid(&["####.", "#####", "#####", "#..##", "#####"]));
+    }
+
+    #[test]
+    fn feathers_the_edges() {
+        let mask = grid(&["###...", "###...", "###..."]);
+        let feathered = MaskRefiner::new().with_feather(1.).refine(&mask);
+        for row in feathered.values.chunks(6) {
+            assert!(row.windows(2).all(|pair| pair[0] > pair[1]));
+            // Symmetric around the edge
+            assert!((row[2] + row[3] - 1.).abs() < 1e-6);
+            assert!(row[0] > 0.99 && row[5] < 0.01);
+        }
+    }
+}
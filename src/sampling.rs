@@ -0,0 +1,311 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use crate::bodypix::Segments;
+
+// How the coarse output grid is read at the pixels of the original image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sampling {
+    // The closest cell, blocky but without blending any values
+    Nearest,
+    #[default]
+    Bilinear,
+    // Catmull-Rom over the 4x4 closest cells, sharper than bilinear
+    Bicubic,
+    // Cells count less the more the original image differs in colour there
+    // from the pixel being sampled, so the mask edges follow the edges in the
+    // image. sigma_spatial is in cells, sigma_range in 8-bit colour levels.
+    // Without the original image to guide it, this samples bilinearly.
+    JointBilateral { sigma_spatial: f32, sigma_range: f32 },
+}
+
+impl Sampling {
+    pub fn joint_bilateral() -> Sampling {
+        Sampling::JointBilateral {
+            sigma_spatial: 1.,
+            sigma_range: 24.,
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "nearest" => Ok(Sampling::Nearest),
+            "bilinear" => Ok(Sampling::Bilinear),
+            "bicubic" => Ok(Sampling::Bicubic),
+            "bilateral" => Ok(Sampling::joint_bilateral()),
+            _ => Err(format!(
+                "expected nearest, bilinear, bicubic or bilateral, got \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+// A mask at the size of the original image, with a foreground probability for
+// every pixel. Sampling the grid once into a mask is much cheaper than
+// interpolating it again for every output image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mask {
+    pub width: usize,
+    pub height: usize,
+    pub values: Vec<f32>, // Row-major
+}
+
+impl Mask {
+    pub fn new(width: usize, height: usize) -> Mask {
+        Mask {
+            width,
+            height,
+            values: vec![0.; width * height],
+        }
+    }
+
+    pub fn from_fn(width: usize, height: usize, mut f: impl FnMut(usize, usize) -> f32) -> Mask {
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                values.push(f(x, y));
+            }
+        }
+        Mask { width, height, values }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[x + y * self.width]
+    }
+}
+
+// What the mask consumers accept: the grid from the network, sampled to the
+// size of the original image, or a mask that already has that size
+pub trait MaskSource {
+    // The guide is the original image, which joint bilateral sampling follows
+    fn to_mask(&self, sampling: Sampling, guide: Option<&DynamicImage>) -> Cow<'_, Mask>;
+}
+
+impl MaskSource for Segments {
+    fn to_mask(&self, sampling: Sampling, guide: Option<&DynamicImage>) -> Cow<'_, Mask> {
+        Cow::Owned(self.upsample(sampling, guide))
+    }
+}
+
+// Already at full resolution, so there is nothing to sample
+impl MaskSource for Mask {
+    fn to_mask(&self, _sampling: Sampling, _guide: Option<&DynamicImage>) -> Cow<'_, Mask> {
+        Cow::Borrowed(self)
+    }
+}
+
+fn cell(segments: &Segments, x: isize, y: isize) -> f32 {
+    let x = x.max(0).min(segments.width as isize - 1) as usize;
+    let y = y.max(0).min(segments.height as isize - 1) as usize;
+    segments.values[x + y * segments.width]
+}
+
+pub(crate) fn nearest(segments: &Segments, grid_x: f32, grid_y: f32) -> f32 {
+    cell(segments, grid_x.round() as isize, grid_y.round() as isize)
+}
+
+pub(crate) fn bilinear(segments: &Segments, grid_x: f32, grid_y: f32) -> f32 {
+    let grid_x = grid_x.min((segments.width - 1) as f32);
+    let grid_y = grid_y.min((segments.height - 1) as f32);
+    let (x, y) = (grid_x.floor() as isize, grid_y.floor() as isize);
+    let (part_x, part_y) = (grid_x - x as f32, grid_y - y as f32);
+
+    let top = cell(segments, x, y) * (1. - part_x) + cell(segments, x + 1, y) * part_x;
+    let bottom = cell(segments, x, y + 1) * (1. - part_x) + cell(segments, x + 1, y + 1) * part_x;
+    top * (1. - part_y) + bottom * part_y
+}
+
+// Weights of the four cells around a position, from one before it to two after
+fn catmull_rom(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        0.5 * (-t3 + 2. * t2 - t),
+        0.5 * (3. * t3 - 5. * t2 + 2.),
+        0.5 * (-3. * t3 + 4. * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
+
+pub(crate) fn bicubic(segments: &Segments, grid_x: f32, grid_y: f32) -> f32 {
+    let grid_x = grid_x.min((segments.width - 1) as f32);
+    let grid_y = grid_y.min((segments.height - 1) as f32);
+    let (x, y) = (grid_x.floor() as isize, grid_y.floor() as isize);
+    let weights_x = catmull_rom(grid_x - x as f32);
+    let weights_y = catmull_rom(grid_y - y as f32);
+
+    let mut value = 0.;
+    for (dy, weight_y) in weights_y.iter().enumerate() {
+        let mut row = 0.;
+        for (dx, weight_x) in weights_x.iter().enumerate() {
+            row += cell(segments, x + dx as isize - 1, y + dy as isize - 1) * weight_x;
+        }
+        value += row * weight_y;
+    }
+    // Catmull-Rom overshoots at sharp edges
+    value.clamp(0., 1.)
+}
+
+// The colour of the original image at the centre of every cell
+fn cell_colours(segments: &Segments, guide: &RgbImage) -> Vec<[f32; 3]> {
+    let max_x = (guide.width() - 1) as f32;
+    let max_y = (guide.height() - 1) as f32;
+    let stride = segments.stride as f32;
+
+    let mut colours = Vec::with_capacity(segments.width * segments.height);
+    for y in 0..segments.height {
+        for x in 0..segments.width {
+            let (orig_x, orig_y) = segments.transform.to_original(x as f32 * stride, y as f32 * stride);
+            let pixel = guide.get_pixel(
+                orig_x.round().max(0.).min(max_x) as u32,
+                orig_y.round().max(0.).min(max_y) as u32,
+            );
+            colours.push([pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]);
+        }
+    }
+    colours
+}
+
+// Joint bilateral upsampling (Kopf et al. 2007), over the 4x4 closest cells
+fn joint_bilateral(
+    segments: &Segments,
+    colours: &[[f32; 3]],
+    colour: [f32; 3],
+    (grid_x, grid_y): (f32, f32),
+    (sigma_spatial, sigma_range): (f32, f32),
+) -> f32 {
+    let spatial_scale = -0.5 / (sigma_spatial * sigma_spatial).max(f32::EPSILON);
+    let range_scale = -0.5 / (sigma_range * sigma_range).max(f32::EPSILON);
+    let (x0, y0) = (grid_x.floor() as isize, grid_y.floor() as isize);
+
+    let mut value = 0.;
+    let mut total_weight = 0.;
+    for y in (y0 - 1).max(0)..(y0 + 3).min(segments.height as isize) {
+        for x in (x0 - 1).max(0)..(x0 + 3).min(segments.width as isize) {
+            let i = x as usize + y as usize * segments.width;
+            let distance = (x as f32 - grid_x).powi(2) + (y as f32 - grid_y).powi(2);
+            let difference: f32 = colours[i]
+                .iter()
+                .zip(colour.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+            let weight = (distance * spatial_scale + difference * range_scale).exp();
+            value += segments.values[i] * weight;
+            total_weight += weight;
+        }
+    }
+
+    if total_weight > f32::EPSILON {
+        value / total_weight
+    } else {
+        bilinear(segments, grid_x, grid_y)
+    }
+}
+
+pub(crate) fn upsample(segments: &Segments, sampling: Sampling, guide: Option<&DynamicImage>) -> Mask {
+    let (width, height) = (segments.orig_width, segments.orig_height);
+    let guide = guide.filter(|guide| guide.dimensions() == (width as u32, height as u32));
+
+    match (sampling, guide) {
+        (
+            Sampling::JointBilateral {
+                sigma_spatial,
+                sigma_range,
+            },
+            Some(guide),
+        ) => {
+            let guide = guide.to_rgb8();
+            let colours = cell_colours(segments, &guide);
+            Mask::from_fn(width, height, |x, y| {
+                let pixel = guide.get_pixel(x as u32, y as u32);
+                let colour = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+                joint_bilateral(
+                    segments,
+                    &colours,
+                    colour,
+                    segments.grid_coords(x, y),
+                    (sigma_spatial, sigma_range),
+                )
+            })
+        }
+        _ => Mask::from_fn(width, height, |x, y| segments.sample(sampling, x, y)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::InputTransform;
+
+    // A 3x2 grid with stride 4, so cell k is centred on pixel 4k of a 12x8 image
+    fn segments() -> Segments {
+        Segments {
+            orig_width: 12,
+            orig_height: 8,
+            width: 3,
+            height: 2,
+            stride: 4,
+            values: vec![0., 0.5, 1., 0.25, 0.5, 0.75],
+            transform: InputTransform::identity(12, 8),
+        }
+    }
+
+    const ALL: [Sampling; 3] = [Sampling::Nearest, Sampling::Bilinear, Sampling::Bicubic];
+
+    #[test]
+    fn cell_centres_are_exact() {
+        let segments = segments();
+        for sampling in ALL {
+            for (i, &value) in segments.values.iter().enumerate() {
+                assert_eq!(segments.sample(sampling, i % 3 * 4, i / 3 * 4), value, "{:?}", sampling);
+            }
+        }
+    }
+
+    #[test]
+    fn right_and_bottom_borders_hold_the_last_cells() {
+        let segments = segments();
+        for sampling in ALL {
+            assert_eq!(segments.sample(sampling, 11, 0), 1., "{:?}", sampling);
+            assert_eq!(segments.sample(sampling, 0, 7), 0.25, "{:?}", sampling);
+            assert_eq!(segments.sample(sampling, 11, 7), 0.75, "{:?}", sampling);
+        }
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_cell() {
+        let segments = segments();
+        assert_eq!(segments.sample(Sampling::Nearest, 1, 0), 0.);
+        assert_eq!(segments.sample(Sampling::Nearest, 5, 1), 0.5);
+        assert_eq!(segments.sample(Sampling::Nearest, 7, 3), 0.75);
+    }
+
+    #[test]
+    fn bilinear_between_cells() {
+        let segments = segments();
+        assert_eq!(segments.sample(Sampling::Bilinear, 2, 0), 0.25);
+        assert_eq!(segments.sample(Sampling::Bilinear, 6, 2), 0.6875);
+    }
+
+    #[test]
+    fn bicubic_between_cells() {
+        let segments = segments();
+        // Weights -1/16, 9/16, 9/16, -1/16 over 0, 0.5, 1 and the repeated 1
+        assert_eq!(segments.sample(Sampling::Bicubic, 6, 0), 0.78125);
+    }
+
+    #[test]
+    fn upsample_covers_the_original_image() {
+        let mask = segments().upsample(Sampling::Bilinear, None);
+        assert_eq!((mask.width, mask.height), (12, 8));
+        assert_eq!(mask.get(11, 7), 0.75);
+        assert_eq!(mask.get(2, 0), 0.25);
+    }
+}
@@ -0,0 +1,265 @@
+use image::{DynamicImage, GenericImageView};
+
+use crate::sampling::{Mask, MaskSource, Sampling};
+
+// Smooths masks over consecutive frames of a video, so the edge of a person
+// doesn't flicker. Each mask is blended with the smoothed mask of the frames
+// before it, as an exponential moving average. With motion compensation, the
+// previous mask is first moved along with the image, found by matching blocks
+// of the previous frame in the current one, so moving people don't leave a
+// trail. A frame that differs too much from the last one is taken as a scene
+// cut, and starts over.
+#[derive(Clone, Debug)]
+pub struct TemporalFilter {
+    smoothing: f32, // Weight of the previous frames, from 0 to 1
+    search_radius: usize,
+    block_size: usize,
+    scene_cut_threshold: f32,
+    sampling: Sampling,
+    previous: Option<(Mask, Mask)>, // The smoothed mask, and the luma of its frame
+}
+
+impl Default for TemporalFilter {
+    fn default() -> Self {
+        TemporalFilter {
+            smoothing: 0.5,
+            search_radius: 0,
+            block_size: 16,
+            scene_cut_threshold: 0.2,
+            sampling: Sampling::default(),
+            previous: None,
+        }
+    }
+}
+
+impl TemporalFilter {
+    pub fn new() -> TemporalFilter {
+        TemporalFilter::default()
+    }
+
+    // How much of the previous frames is kept. Higher is steadier, but slower
+    // to follow movement.
+    pub fn with_smoothing(mut self, smoothing: f32) -> TemporalFilter {
+        self.smoothing = smoothing.clamp(0., 1.);
+        self
+    }
+
+    // Follows movement up to this many pixels per frame. Off at 0, the default,
+    // as the search takes longer the larger the radius.
+    pub fn with_motion_compensation(mut self, search_radius: usize) -> TemporalFilter {
+        self.search_radius = search_radius;
+        self
+    }
+
+    // Size of the blocks that are matched between frames
+    pub fn with_block_size(mut self, block_size: usize) -> TemporalFilter {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    // Mean brightness change, from 0 to 1, above which a frame starts a new scene
+    pub fn with_scene_cut_threshold(mut self, threshold: f32) -> TemporalFilter {
+        self.scene_cut_threshold = threshold.clamp(0., 1.);
+        self
+    }
+
+    // How masks from the network are sampled at the size of the frame
+    pub fn with_sampling(mut self, sampling: Sampling) -> TemporalFilter {
+        self.sampling = sampling;
+        self
+    }
+
+    // Forgets the previous frames, like when a new video starts
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    pub fn filter(&mut self, mask: &impl MaskSource, frame: &DynamicImage) -> Mask {
+        let mask = mask.to_mask(self.sampling, Some(frame)).into_owned();
+        let luma = luma(frame);
+
+        let smoothed = match self.previous.take() {
+            Some((previous_mask, previous_luma)) if !self.is_scene_cut(&previous_luma, &luma, &mask) => {
+                let previous_mask = if self.search_radius > 0 {
+                    let motion = block_motion(&previous_luma, &luma, self.block_size, self.search_radius);
+                    warp(&previous_mask, &motion, self.block_size)
+                } else {
+                    previous_mask
+                };
+
+                let values = mask
+                    .values
+                    .iter()
+                    .zip(&previous_mask.values)
+                    .map(|(current, previous)| current * (1. - self.smoothing) + previous * self.smoothing)
+                    .collect();
+                Mask {
+                    width: mask.width,
+                    height: mask.height,
+                    values,
+                }
+            }
+            _ => mask,
+        };
+
+        self.previous = Some((smoothed.clone(), luma));
+        smoothed
+    }
+
+    fn is_scene_cut(&self, previous_luma: &Mask, luma: &Mask, mask: &Mask) -> bool {
+        if (previous_luma.width, previous_luma.height) != (luma.width, luma.height)
+            || (mask.width, mask.height) != (luma.width, luma.height)
+        {
+            return true;
+        }
+        let difference: f32 = previous_luma
+            .values
+            .iter()
+            .zip(&luma.values)
+            .map(|(previous, current)| (previous - current).abs())
+            .sum();
+        difference / luma.values.len().max(1) as f32 > self.scene_cut_threshold
+    }
+}
+
+// The brightness of a frame from 0 to 1, laid out like a mask
+fn luma(frame: &DynamicImage) -> Mask {
+    let (width, height) = frame.dimensions();
+    Mask {
+        width: width as usize,
+        height: height as usize,
+        values: frame.to_luma8().pixels().map(|pixel| pixel[0] as f32 / 255.).collect(),
+    }
+}
+
+fn clamped(image: &Mask, x: isize, y: isize) -> f32 {
+    let x = x.max(0).min(image.width as isize - 1) as usize;
+    let y = y.max(0).min(image.height as isize - 1) as usize;
+    image.values[x + y * image.width]
+}
+
+// Blocks along a side, with the last one cut short
+fn block_count(size: usize, block_size: usize) -> usize {
+    size.div_ceil(block_size)
+}
+
+// For each block of the current frame, where in the previous frame it came
+// from, as the offset with the smallest sum of absolute differences. Every
+// other pixel is compared, which is plenty for whole blocks.
+fn block_motion(previous: &Mask, current: &Mask, block_size: usize, search_radius: usize) -> Vec<(isize, isize)> {
+    let blocks_x = block_count(current.width, block_size);
+    let blocks_y = block_count(current.height, block_size);
+    let radius = search_radius as isize;
+
+    let mut motion = Vec::with_capacity(blocks_x * blocks_y);
+    for block_y in 0..blocks_y {
+        for block_x in 0..blocks_x {
+            let (left, top) = (block_x * block_size, block_y * block_size);
+            let right = (left + block_size).min(current.width);
+            let bottom = (top + block_size).min(current.height);
+
+            let difference = |dx: isize, dy: isize| {
+                let mut sum = 0.;
+                for y in (top..bottom).step_by(2) {
+                    for x in (left..right).step_by(2) {
+                        let moved = clamped(previous, x as isize + dx, y as isize + dy);
+                        sum += (current.values[x + y * current.width] - moved).abs();
+                    }
+                }
+                sum
+            };
+
+            // Standing still wins ties, so flat areas don't drift
+            let mut best = (0, 0);
+            let mut best_difference = difference(0, 0);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let candidate = difference(dx, dy);
+                    if candidate < best_difference {
+                        best = (dx, dy);
+                        best_difference = candidate;
+                    }
+                }
+            }
+            motion.push(best);
+        }
+    }
+    motion
+}
+
+// Moves the previous mask along with the blocks of the image
+fn warp(previous: &Mask, motion: &[(isize, isize)], block_size: usize) -> Mask {
+    let blocks_x = block_count(previous.width, block_size);
+    Mask::from_fn(previous.width, previous.height, |x, y| {
+        let (dx, dy) = motion[x / block_size + (y / block_size) * blocks_x];
+        clamped(previous, x as isize + dx, y as isize + dy)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn frame(width: u32, height: u32, level: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([level])))
+    }
+
+    fn filled(width: usize, height: usize, value: f32) -> Mask {
+        Mask::from_fn(width, height, |_, _| value)
+    }
+
+    #[test]
+    fn blends_with_the_previous_frames() {
+        let mut filter = TemporalFilter::new().with_smoothing(0.25);
+        let frame = frame(4, 4, 128);
+        assert_eq!(filter.filter(&filled(4, 4, 1.), &frame), filled(4, 4, 1.));
+        assert_eq!(filter.filter(&filled(4, 4, 0.), &frame), filled(4, 4, 0.25));
+        assert_eq!(filter.filter(&filled(4, 4, 0.), &frame), filled(4, 4, 0.0625));
+
+        filter.reset();
+        assert_eq!(filter.filter(&filled(4, 4, 0.), &frame), filled(4, 4, 0.));
+    }
+
+    #[test]
+    fn starts_over_at_a_scene_cut() {
+        let mut filter = TemporalFilter::new().with_smoothing(0.5);
+        filter.filter(&filled(4, 4, 1.), &frame(4, 4, 0));
+        assert_eq!(filter.filter(&filled(4, 4, 0.), &frame(4, 4, 255)), filled(4, 4, 0.));
+
+        // Below the threshold, the frames are blended
+        filter.filter(&filled(4, 4, 1.), &frame(4, 4, 100));
+        assert_eq!(filter.filter(&filled(4, 4, 0.), &frame(4, 4, 110)), filled(4, 4, 0.5));
+    }
+
+    #[test]
+    fn starts_over_when_the_size_changes() {
+        let mut filter = TemporalFilter::new().with_smoothing(0.5);
+        filter.filter(&filled(4, 4, 1.), &frame(4, 4, 128));
+        assert_eq!(filter.filter(&filled(6, 4, 0.), &frame(6, 4, 128)), filled(6, 4, 0.));
+    }
+
+    #[test]
+    fn motion_compensation_follows_the_image() {
+        // A bright square with the mask on it, two pixels further right in the second frame
+        let square = |left: u32| {
+            let image = GrayImage::from_fn(16, 8, |x, y| {
+                Luma([if (left..left + 4).contains(&x) && (2..6).contains(&y) { 255 } else { 0 }])
+            });
+            let mask = Mask::from_fn(16, 8, |x, y| image.get_pixel(x as u32, y as u32)[0] as f32 / 255.);
+            (mask, DynamicImage::ImageLuma8(image))
+        };
+        let ((first_mask, first), (second_mask, second)) = (square(4), square(6));
+
+        let mut still = TemporalFilter::new().with_smoothing(0.5);
+        still.filter(&first_mask, &first);
+        assert_eq!(still.filter(&second_mask, &second).get(9, 3), 0.5);
+
+        let mut moving = TemporalFilter::new()
+            .with_smoothing(0.5)
+            .with_motion_compensation(3)
+            .with_block_size(4);
+        moving.filter(&first_mask, &first);
+        assert_eq!(moving.filter(&second_mask, &second), second_mask);
+    }
+}
@@ -0,0 +1,642 @@
+// Converts TensorFlow.js graph models (model.json plus weight shards) into a
+// frozen GraphDef that Tensorflow can import. The topology in model.json is a
+// GraphDef in protobuf's JSON mapping, with the Const values moved out into
+// the shards, so this puts the weights back and writes the protobuf encoding.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::protobuf::Writer;
+
+#[derive(Debug)]
+pub enum ConvertError {
+    Io { path: PathBuf, source: std::io::Error },
+    InvalidModel(String),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::Io { path, source } => write!(f, "Could not read {:?}: {}", path, source),
+            ConvertError::InvalidModel(msg) => write!(f, "Invalid tfjs model: {}", msg),
+        }
+    }
+}
+
+impl Error for ConvertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConvertError::Io { source, .. } => Some(source),
+            ConvertError::InvalidModel(_) => None,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, ConvertError>;
+
+fn invalid<T>(msg: String) -> Result<T> {
+    Err(ConvertError::InvalidModel(msg))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Returns the serialized GraphDef for a model.json, reading the shards next to it
+pub fn convert(model_json: &Path) -> Result<Vec<u8>> {
+    let model: Value =
+        serde_json::from_slice(&read_file(model_json)?).map_err(|err| ConvertError::InvalidModel(err.to_string()))?;
+    let dir = model_json.parent().unwrap_or_else(|| Path::new("."));
+
+    let weights = read_weights(&model, dir)?;
+    let topology = match model.get("modelTopology") {
+        Some(topology) => topology,
+        None => return invalid("no modelTopology, only graph models are supported".to_string()),
+    };
+
+    let mut graph_def = Writer::new();
+    for node in array(topology, "node")? {
+        graph_def.bytes(1, &encode_node(node, &weights)?);
+    }
+    if let Some(versions) = topology.get("versions") {
+        let mut version_def = Writer::new();
+        if let Some(producer) = versions.get("producer") {
+            version_def.varint_field(1, int(producer)? as u64);
+        }
+        if let Some(min_consumer) = versions.get("minConsumer") {
+            version_def.varint_field(2, int(min_consumer)? as u64);
+        }
+        graph_def.bytes(4, &version_def.0);
+    }
+
+    Ok(graph_def.0)
+}
+
+pub fn convert_to_file(model_json: &Path, output: &Path) -> Result<()> {
+    let graph_def = convert(model_json)?;
+    fs::write(output, graph_def).map_err(|source| ConvertError::Io {
+        path: output.to_path_buf(),
+        source,
+    })
+}
+
+// A Const value, ready to go into a TensorProto
+struct Weight {
+    data_type: u64,
+    shape: Vec<i64>,
+    content: Vec<u8>, // Little endian, like tensor_content
+}
+
+// The weights of each group are laid out one after the other, across all of its shards
+fn read_weights(model: &Value, dir: &Path) -> Result<HashMap<String, Weight>> {
+    let mut weights = HashMap::new();
+    for group in array(model, "weightsManifest")? {
+        let mut data = Vec::new();
+        for shard in array(group, "paths")? {
+            match shard.as_str() {
+                Some(shard) => data.extend_from_slice(&read_file(&dir.join(shard))?),
+                None => return invalid(format!("shard path {} is not a string", shard)),
+            }
+        }
+
+        let mut offset = 0;
+        for entry in array(group, "weights")? {
+            let name = string(entry, "name")?;
+            let weight = read_weight(entry, &data, &mut offset)?;
+            weights.insert(name.to_string(), weight);
+        }
+    }
+    Ok(weights)
+}
+
+fn read_weight(entry: &Value, data: &[u8], offset: &mut usize) -> Result<Weight> {
+    let name = string(entry, "name")?;
+    let dtype = string(entry, "dtype")?;
+    let shape = array(entry, "shape")?.iter().map(int).collect::<Result<Vec<i64>>>()?;
+    let count = shape.iter().product::<i64>() as usize;
+
+    let quantization = entry.get("quantization");
+    let stored_dtype = match quantization {
+        Some(quantization) => string(quantization, "dtype")?,
+        None => dtype,
+    };
+    let size = match stored_dtype {
+        "float32" | "int32" => 4,
+        "float16" | "uint16" => 2,
+        "uint8" | "bool" => 1,
+        _ => return invalid(format!("weight {} has unsupported dtype {}", name, stored_dtype)),
+    };
+
+    let end = *offset + count * size;
+    if end > data.len() {
+        return invalid(format!("the shards end before weight {}", name));
+    }
+    let bytes = &data[*offset..end];
+    *offset = end;
+
+    let content = match quantization {
+        None => bytes.to_vec(),
+        Some(quantization) => {
+            let values = dequantize(stored_dtype, quantization, bytes)?;
+            let mut content = Vec::with_capacity(values.len() * 4);
+            for value in values {
+                if dtype == "int32" {
+                    content.extend_from_slice(&(value.round() as i32).to_le_bytes());
+                } else {
+                    content.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            content
+        }
+    };
+
+    Ok(Weight {
+        data_type: weight_data_type(name, dtype)?,
+        shape,
+        content,
+    })
+}
+
+// Quantized weights are stored as value = quantized * scale + min, or as float16
+fn dequantize(stored_dtype: &str, quantization: &Value, bytes: &[u8]) -> Result<Vec<f32>> {
+    if stored_dtype == "float16" {
+        return Ok(bytes
+            .chunks_exact(2)
+            .map(|half| f16_to_f32(u16::from_le_bytes([half[0], half[1]])))
+            .collect());
+    }
+
+    let scale = float(quantization.get("scale").unwrap_or(&Value::Null))?;
+    let min = float(quantization.get("min").unwrap_or(&Value::Null))?;
+    match stored_dtype {
+        "uint8" => Ok(bytes.iter().map(|quantized| *quantized as f32 * scale + min).collect()),
+        "uint16" => Ok(bytes
+            .chunks_exact(2)
+            .map(|quantized| u16::from_le_bytes([quantized[0], quantized[1]]) as f32 * scale + min)
+            .collect()),
+        _ => invalid(format!("{} weights can't be quantized", stored_dtype)),
+    }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half >> 15) as u32) << 31;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        0 => {
+            // Subnormal, which is a normal number as f32
+            let value = mantissa as f32 / (1 << 24) as f32;
+            return if sign == 0 { value } else { -value };
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+fn weight_data_type(name: &str, dtype: &str) -> Result<u64> {
+    match dtype {
+        "float32" => Ok(DT_FLOAT),
+        "int32" => Ok(DT_INT32),
+        "bool" => Ok(DT_BOOL),
+        _ => invalid(format!("weight {} has unsupported dtype {}", name, dtype)),
+    }
+}
+
+const DT_FLOAT: u64 = 1;
+const DT_INT32: u64 = 3;
+const DT_BOOL: u64 = 10;
+
+const DATA_TYPES: [(&str, u64); 12] = [
+    ("DT_FLOAT", DT_FLOAT),
+    ("DT_DOUBLE", 2),
+    ("DT_INT32", DT_INT32),
+    ("DT_UINT8", 4),
+    ("DT_INT16", 5),
+    ("DT_INT8", 6),
+    ("DT_STRING", 7),
+    ("DT_INT64", 9),
+    ("DT_BOOL", DT_BOOL),
+    ("DT_UINT16", 17),
+    ("DT_HALF", 19),
+    ("DT_UINT32", 22),
+];
+
+fn data_type(value: &Value) -> Result<u64> {
+    if let Some(number) = value.as_u64() {
+        return Ok(number);
+    }
+    let name = value.as_str().unwrap_or_default();
+    match DATA_TYPES.iter().find(|(type_name, _)| *type_name == name) {
+        Some((_, number)) => Ok(*number),
+        None => invalid(format!("unsupported data type {}", value)),
+    }
+}
+
+// NodeDef: name = 1, op = 2, input = 3, device = 4, attr = 5
+fn encode_node(node: &Value, weights: &HashMap<String, Weight>) -> Result<Vec<u8>> {
+    let name = string(node, "name")?;
+    let op = match string(node, "op")? {
+        // tfjs' name for the fused op that Tensorflow keeps internal
+        "FusedDepthwiseConv2dNative" => "_FusedDepthwiseConv2dNative",
+        op => op,
+    };
+    let weight = if op == "Const" { weights.get(name) } else { None };
+
+    let mut node_def = Writer::new();
+    node_def.string(1, name);
+    node_def.string(2, op);
+    if let Some(inputs) = node.get("input").and_then(Value::as_array) {
+        for input in inputs {
+            node_def.string(3, input.as_str().unwrap_or_default());
+        }
+    }
+    if let Some(device) = node.get("device").and_then(Value::as_str) {
+        node_def.string(4, device);
+    }
+
+    let mut attrs = Vec::new();
+    if let Some(attr) = node.get("attr").and_then(Value::as_object) {
+        for (key, value) in attr {
+            // Replaced below with the values from the shards
+            if weight.is_some() && (key == "value" || key == "dtype") {
+                continue;
+            }
+            let attr_value = encode_attr(value).map_err(|err| match err {
+                ConvertError::InvalidModel(msg) => ConvertError::InvalidModel(format!("{}.{}: {}", name, key, msg)),
+                err => err,
+            })?;
+            attrs.push((key.as_str(), attr_value));
+        }
+    }
+    if let Some(weight) = weight {
+        let mut type_attr = Writer::new();
+        type_attr.varint_field(6, weight.data_type);
+        attrs.push(("dtype", type_attr.0));
+
+        let mut value_attr = Writer::new();
+        value_attr.bytes(8, &encode_weight(weight));
+        attrs.push(("value", value_attr.0));
+    }
+
+    // Maps are repeated entries of key = 1, value = 2
+    for (key, value) in attrs {
+        let mut entry = Writer::new();
+        entry.string(1, key);
+        entry.bytes(2, &value);
+        node_def.bytes(5, &entry.0);
+    }
+
+    Ok(node_def.0)
+}
+
+// AttrValue: list = 1, s = 2, i = 3, f = 4, b = 5, type = 6, shape = 7, tensor = 8, placeholder = 9
+fn encode_attr(value: &Value) -> Result<Vec<u8>> {
+    let mut attr_value = Writer::new();
+    if let Some(list) = value.get("list") {
+        attr_value.bytes(1, &encode_list(list)?);
+    } else if let Some(s) = value.get("s") {
+        attr_value.bytes(2, &base64(s)?);
+    } else if let Some(i) = value.get("i") {
+        attr_value.varint_field(3, int(i)? as u64);
+    } else if let Some(f) = value.get("f") {
+        attr_value.float_field(4, float(f)?);
+    } else if let Some(b) = value.get("b") {
+        attr_value.varint_field(5, b.as_bool().unwrap_or_default() as u64);
+    } else if let Some(data_type_value) = value.get("type") {
+        attr_value.varint_field(6, data_type(data_type_value)?);
+    } else if let Some(shape) = value.get("shape") {
+        attr_value.bytes(7, &encode_shape(shape)?);
+    } else if let Some(tensor) = value.get("tensor") {
+        attr_value.bytes(8, &encode_tensor(tensor)?);
+    } else if let Some(placeholder) = value.get("placeholder") {
+        attr_value.string(9, placeholder.as_str().unwrap_or_default());
+    } else if value.get("func").is_some() {
+        return invalid("function attributes are not supported".to_string());
+    }
+    Ok(attr_value.0)
+}
+
+// ListValue: s = 2, i = 3, f = 4, b = 5, type = 6, shape = 7, tensor = 8
+fn encode_list(list: &Value) -> Result<Vec<u8>> {
+    let mut list_value = Writer::new();
+    if let Some(values) = list.get("s").and_then(Value::as_array) {
+        for s in values {
+            list_value.bytes(2, &base64(s)?);
+        }
+    }
+    if let Some(values) = list.get("i").and_then(Value::as_array) {
+        let mut packed = Writer::new();
+        for i in values {
+            packed.varint(int(i)? as u64);
+        }
+        list_value.bytes(3, &packed.0);
+    }
+    if let Some(values) = list.get("f").and_then(Value::as_array) {
+        let mut packed = Writer::new();
+        for f in values {
+            packed.0.extend_from_slice(&float(f)?.to_le_bytes());
+        }
+        list_value.bytes(4, &packed.0);
+    }
+    if let Some(values) = list.get("b").and_then(Value::as_array) {
+        let mut packed = Writer::new();
+        for b in values {
+            packed.varint(b.as_bool().unwrap_or_default() as u64);
+        }
+        list_value.bytes(5, &packed.0);
+    }
+    if let Some(values) = list.get("type").and_then(Value::as_array) {
+        let mut packed = Writer::new();
+        for data_type_value in values {
+            packed.varint(data_type(data_type_value)?);
+        }
+        list_value.bytes(6, &packed.0);
+    }
+    if let Some(values) = list.get("shape").and_then(Value::as_array) {
+        for shape in values {
+            list_value.bytes(7, &encode_shape(shape)?);
+        }
+    }
+    if let Some(values) = list.get("tensor").and_then(Value::as_array) {
+        for tensor in values {
+            list_value.bytes(8, &encode_tensor(tensor)?);
+        }
+    }
+    Ok(list_value.0)
+}
+
+// TensorShapeProto: dim = 2 (size = 1, name = 2), unknown_rank = 3
+fn encode_shape(shape: &Value) -> Result<Vec<u8>> {
+    let mut shape_proto = Writer::new();
+    if let Some(dims) = shape.get("dim").and_then(Value::as_array) {
+        for dim in dims {
+            let mut dim_proto = Writer::new();
+            if let Some(size) = dim.get("size") {
+                dim_proto.varint_field(1, int(size)? as u64);
+            }
+            if let Some(name) = dim.get("name").and_then(Value::as_str) {
+                dim_proto.string(2, name);
+            }
+            shape_proto.bytes(2, &dim_proto.0);
+        }
+    }
+    if shape.get("unknownRank").and_then(Value::as_bool).unwrap_or_default() {
+        shape_proto.varint_field(3, 1);
+    }
+    Ok(shape_proto.0)
+}
+
+fn encode_dims(dims: &[i64]) -> Vec<u8> {
+    let mut shape_proto = Writer::new();
+    for size in dims {
+        let mut dim_proto = Writer::new();
+        dim_proto.varint_field(1, *size as u64);
+        shape_proto.bytes(2, &dim_proto.0);
+    }
+    shape_proto.0
+}
+
+// TensorProto: dtype = 1, tensor_shape = 2, version_number = 3, tensor_content = 4,
+// float_val = 5, int_val = 7, string_val = 8, int64_val = 10, bool_val = 11
+fn encode_tensor(tensor: &Value) -> Result<Vec<u8>> {
+    let fields = match tensor.as_object() {
+        Some(fields) => fields,
+        None => return invalid(format!("tensor {} is not an object", tensor)),
+    };
+
+    let mut tensor_proto = Writer::new();
+    for (key, value) in fields {
+        let values = || value.as_array().map(Vec::as_slice).unwrap_or_default();
+        match key.as_str() {
+            "dtype" => tensor_proto.varint_field(1, data_type(value)?),
+            "tensorShape" => tensor_proto.bytes(2, &encode_shape(value)?),
+            "versionNumber" => tensor_proto.varint_field(3, int(value)? as u64),
+            "tensorContent" => tensor_proto.bytes(4, &base64(value)?),
+            "floatVal" => {
+                let mut packed = Writer::new();
+                for f in values() {
+                    packed.0.extend_from_slice(&float(f)?.to_le_bytes());
+                }
+                tensor_proto.bytes(5, &packed.0);
+            }
+            "intVal" | "int64Val" | "boolVal" => {
+                let mut packed = Writer::new();
+                for i in values() {
+                    packed.varint(match i.as_bool() {
+                        Some(b) => b as u64,
+                        None => int(i)? as u64,
+                    });
+                }
+                let field = match key.as_str() {
+                    "intVal" => 7,
+                    "int64Val" => 10,
+                    _ => 11,
+                };
+                tensor_proto.bytes(field, &packed.0);
+            }
+            "stringVal" => {
+                for s in values() {
+                    tensor_proto.bytes(8, &base64(s)?);
+                }
+            }
+            _ => return invalid(format!("unsupported tensor field {}", key)),
+        }
+    }
+    Ok(tensor_proto.0)
+}
+
+fn encode_weight(weight: &Weight) -> Vec<u8> {
+    let mut tensor_proto = Writer::new();
+    tensor_proto.varint_field(1, weight.data_type);
+    tensor_proto.bytes(2, &encode_dims(&weight.shape));
+    tensor_proto.bytes(4, &weight.content);
+    tensor_proto.0
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>> {
+    match value.get(key).and_then(Value::as_array) {
+        Some(array) => Ok(array),
+        None => invalid(format!("expected an array \"{}\"", key)),
+    }
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    match value.get(key).and_then(Value::as_str) {
+        Some(string) => Ok(string),
+        None => invalid(format!("expected a string \"{}\"", key)),
+    }
+}
+
+// The JSON mapping writes 64 bit integers as strings
+fn int(value: &Value) -> Result<i64> {
+    let parsed = match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(string) => string.parse().ok(),
+        _ => None,
+    };
+    match parsed {
+        Some(int) => Ok(int),
+        None => invalid(format!("expected an integer, got {}", value)),
+    }
+}
+
+// ...and non-finite floats as strings
+fn float(value: &Value) -> Result<f32> {
+    match value {
+        Value::Number(number) => Ok(number.as_f64().unwrap_or_default() as f32),
+        Value::String(string) if string == "NaN" => Ok(f32::NAN),
+        Value::String(string) if string == "Infinity" => Ok(f32::INFINITY),
+        Value::String(string) if string == "-Infinity" => Ok(f32::NEG_INFINITY),
+        _ => invalid(format!("expected a number, got {}", value)),
+    }
+}
+
+// Bytes fields are base64 in the JSON mapping
+fn base64(value: &Value) -> Result<Vec<u8>> {
+    let text = match value.as_str() {
+        Some(text) => text,
+        None => return invalid(format!("expected base64, got {}", value)),
+    };
+
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            _ => return invalid(format!("invalid base64 \"{}\"", text)),
+        };
+        buffer = buffer << 6 | sextet as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn base64_decodes_padded_unpadded_and_url_safe() {
+        assert_eq!(base64(&json!("aGVsbG8=")).unwrap(), b"hello");
+        assert_eq!(base64(&json!("YWI")).unwrap(), b"ab");
+        assert_eq!(base64(&json!("+/8=")).unwrap(), [0xfb, 0xff]);
+        assert_eq!(base64(&json!("-_8=")).unwrap(), [0xfb, 0xff]);
+        assert!(base64(&json!("a*")).is_err());
+        assert!(base64(&json!(1)).is_err());
+    }
+
+    #[test]
+    fn f16_to_f32_covers_every_class() {
+        assert_eq!(f16_to_f32(0x3c00), 1.);
+        assert_eq!(f16_to_f32(0xc000), -2.);
+        assert_eq!(f16_to_f32(0x3555), 0.333_251_95);
+        assert_eq!(f16_to_f32(0x0000).to_bits(), 0);
+        assert_eq!(f16_to_f32(0x8000).to_bits(), 0x8000_0000);
+        // The smallest subnormal, and the largest
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x8001), -(2f32.powi(-24)));
+        assert_eq!(f16_to_f32(0x03ff), 1023. * 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn dequantize_with_scale_and_min() {
+        let quantization = json!({ "dtype": "uint8", "scale": 0.5, "min": -1.0 });
+        assert_eq!(dequantize("uint8", &quantization, &[0, 2, 255]).unwrap(), [-1., 0., 126.5]);
+
+        let quantization = json!({ "dtype": "uint16", "scale": 0.25, "min": 1.0 });
+        assert_eq!(dequantize("uint16", &quantization, &[0, 1, 3, 0]).unwrap(), [65., 1.75]);
+
+        // float16 has no scale or min
+        let quantization = json!({ "dtype": "float16" });
+        assert_eq!(dequantize("float16", &quantization, &[0x00, 0x3c, 0x00, 0xc0]).unwrap(), [1., -2.]);
+
+        assert!(dequantize("int32", &json!({ "scale": 1.0, "min": 0.0 }), &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn converts_a_minimal_model() {
+        let dir = std::env::temp_dir().join(format!("bodypix-tfjs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let model = json!({
+            "modelTopology": {
+                "node": [
+                    {
+                        "name": "w",
+                        "op": "Const",
+                        "attr": {
+                            "value": { "tensor": { "dtype": "DT_FLOAT", "tensorShape": { "dim": [{ "size": "2" }] } } },
+                            "dtype": { "type": "DT_FLOAT" }
+                        }
+                    },
+                    { "name": "out", "op": "Identity", "input": ["w"], "attr": { "T": { "type": "DT_FLOAT" } } }
+                ],
+                "versions": { "producer": 27 }
+            },
+            // The weight is split across both shards
+            "weightsManifest": [{
+                "paths": ["group1-shard1of2.bin", "group1-shard2of2.bin"],
+                "weights": [{ "name": "w", "shape": [2], "dtype": "float32" }]
+            }]
+        });
+        fs::write(dir.join("model.json"), model.to_string()).unwrap();
+        fs::write(dir.join("group1-shard1of2.bin"), 1f32.to_le_bytes()).unwrap();
+        fs::write(dir.join("group1-shard2of2.bin"), 2f32.to_le_bytes()).unwrap();
+
+        let graph_def = convert(&dir.join("model.json"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut expected = Vec::new();
+        // node { name: "w" op: "Const" attr { dtype } attr { value } }
+        expected.extend_from_slice(&[0x0a, 54, 0x0a, 1, b'w', 0x12, 5]);
+        expected.extend_from_slice(b"Const");
+        expected.extend_from_slice(&[0x2a, 11, 0x0a, 5]);
+        expected.extend_from_slice(b"dtype");
+        expected.extend_from_slice(&[0x12, 2, 0x30, 1]);
+        expected.extend_from_slice(&[0x2a, 29, 0x0a, 5]);
+        expected.extend_from_slice(b"value");
+        // AttrValue.tensor: dtype, tensor_shape { dim { size: 2 } }, tensor_content
+        expected.extend_from_slice(&[0x12, 20, 0x42, 18, 0x08, 1, 0x12, 4, 0x12, 2, 0x08, 2, 0x22, 8]);
+        expected.extend_from_slice(&[0, 0, 0x80, 0x3f, 0, 0, 0, 0x40]);
+        // node { name: "out" op: "Identity" input: "w" attr { T } }
+        expected.extend_from_slice(&[0x0a, 27, 0x0a, 3]);
+        expected.extend_from_slice(b"out");
+        expected.extend_from_slice(&[0x12, 8]);
+        expected.extend_from_slice(b"Identity");
+        expected.extend_from_slice(&[0x1a, 1, b'w', 0x2a, 7, 0x0a, 1, b'T', 0x12, 2, 0x30, 1]);
+        // versions { producer: 27 }
+        expected.extend_from_slice(&[0x22, 2, 0x08, 27]);
+        assert_eq!(graph_def.unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_shards_that_end_early() {
+        let entry = json!({ "name": "w", "shape": [2], "dtype": "float32" });
+        assert!(read_weight(&entry, &[0; 4], &mut 0).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::error::{BodyPixError, Result};
+use crate::video::y4m::Y4mReader;
+use crate::video::{FrameSource, VideoSink};
+
+fn spawn_failed(err: std::io::Error) -> BodyPixError {
+    BodyPixError::Io(std::io::Error::new(
+        err.kind(),
+        format!("Could not run ffmpeg, is it installed and on the PATH? {}", err),
+    ))
+}
+
+fn wait_for(child: &mut Child) -> Result<()> {
+    let status = child.wait().map_err(BodyPixError::Io)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(BodyPixError::Io(std::io::Error::other(format!("ffmpeg exited with {}", status))))
+    }
+}
+
+// Decodes any container ffmpeg understands, piping the frames back as Y4M
+pub struct FfmpegSource {
+    reader: Y4mReader<ChildStdout>,
+    child: Child,
+}
+
+impl FfmpegSource {
+    pub fn open(path: &Path) -> Result<FfmpegSource> {
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-i"])
+            .arg(path)
+            .args(["-f", "yuv4mpegpipe", "-pix_fmt", "yuv420p", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(spawn_failed)?;
+
+        let reader = Y4mReader::new(child.stdout.take().unwrap())?;
+        Ok(FfmpegSource { reader, child })
+    }
+}
+
+impl FrameSource for FfmpegSource {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        let frame = self.reader.next_frame()?;
+        if frame.is_none() {
+            wait_for(&mut self.child)?;
+        }
+        Ok(frame)
+    }
+
+    fn frame_rate(&self) -> Option<(u32, u32)> {
+        self.reader.frame_rate()
+    }
+}
+
+impl Drop for FfmpegSource {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Encodes frames with ffmpeg, picking the codec from the file extension. With
+// alpha, the frames are sent as RGBA and need a container that can carry it
+// (.webm as VP9, or .mov as ProRes 4444).
+pub struct FfmpegSink {
+    path: PathBuf,
+    frame_rate: (u32, u32),
+    alpha: bool,
+    encoder: Option<(Child, ChildStdin, (u32, u32))>,
+}
+
+impl FfmpegSink {
+    pub fn create(path: &Path, frame_rate: (u32, u32), alpha: bool) -> Result<FfmpegSink> {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if alpha && extension != "webm" && extension != "mov" {
+            return Err(BodyPixError::UnsupportedImage(format!(
+                "can't encode alpha into \"{}\", use .webm or .mov",
+                path.display()
+            )));
+        }
+
+        Ok(FfmpegSink {
+            path: path.to_path_buf(),
+            frame_rate,
+            alpha,
+            encoder: None,
+        })
+    }
+
+    fn start(&self, width: u32, height: u32) -> Result<(Child, ChildStdin)> {
+        let (input_format, codec_args): (&str, &[&str]) = if !self.alpha {
+            ("rgb24", &["-pix_fmt", "yuv420p"])
+        } else if self.path.extension().map(|ext| ext == "mov").unwrap_or(false) {
+            ("rgba", &["-c:v", "prores_ks", "-profile:v", "4444", "-pix_fmt", "yuva444p10le"])
+        } else {
+            ("rgba", &["-c:v", "libvpx-vp9", "-pix_fmt", "yuva420p"])
+        };
+
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y", "-f", "rawvideo", "-pix_fmt", input_format])
+            .args(["-s", &format!("{}x{}", width, height)])
+            .args(["-r", &format!("{}/{}", self.frame_rate.0, self.frame_rate.1)])
+            .args(["-i", "-"])
+            .args(codec_args)
+            .arg(&self.path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(spawn_failed)?;
+
+        let stdin = child.stdin.take().unwrap();
+        Ok((child, stdin))
+    }
+}
+
+impl VideoSink for FfmpegSink {
+    fn write_frame(&mut self, frame: &DynamicImage) -> Result<()> {
+        let dimensions = frame.dimensions();
+        if self.encoder.is_none() {
+            let (child, stdin) = self.start(dimensions.0, dimensions.1)?;
+            self.encoder = Some((child, stdin, dimensions));
+        }
+
+        let (_, stdin, stream_dimensions) = self.encoder.as_mut().unwrap();
+        if *stream_dimensions != dimensions {
+            return Err(BodyPixError::UnsupportedImage(format!(
+                "frame is {}x{}, but the video is {}x{}",
+                dimensions.0, dimensions.1, stream_dimensions.0, stream_dimensions.1
+            )));
+        }
+
+        let data = if self.alpha {
+            frame.to_rgba8().into_raw()
+        } else {
+            frame.to_rgb8().into_raw()
+        };
+        stdin.write_all(&data).map_err(BodyPixError::Io)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.encoder.take() {
+            Some((mut child, stdin, _)) => {
+                // Closing stdin lets ffmpeg finish the file
+                drop(stdin);
+                wait_for(&mut child)
+            }
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Frame sources and sinks, the live segmentation loop and video file processing.
+
+mod ffmpeg;
+mod sources;
+#[cfg(all(feature = "v4l2", target_os = "linux"))]
+mod v4l2;
+mod y4m;
+mod yuv;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use image::DynamicImage;
+
+use crate::bodypix::{BodyPix, Segments};
+use crate::compositor::Compositor;
+use crate::error::{BodyPixError, Result};
+use crate::sampling::Mask;
+use crate::temporal::TemporalFilter;
+
+pub use self::ffmpeg::{FfmpegSink, FfmpegSource};
+pub use self::sources::{ImageSequence, RawVideoFile};
+#[cfg(all(feature = "v4l2", target_os = "linux"))]
+pub use self::v4l2::V4l2Camera;
+pub use self::y4m::{Y4mColour, Y4mReader, Y4mWriter};
+pub use self::yuv::{to_rgb, PixelFormat};
+
+pub trait FrameSource {
+    // None once the source has run out of frames
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>>;
+
+    // As (numerator, denominator) frames per second, for sources that know it
+    fn frame_rate(&self) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for Box<S> {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        (**self).next_frame()
+    }
+
+    fn frame_rate(&self) -> Option<(u32, u32)> {
+        (**self).frame_rate()
+    }
+}
+
+pub trait VideoSink {
+    fn write_frame(&mut self, frame: &DynamicImage) -> Result<()>;
+
+    // Flushes, and waits for any encoder to complete the file
+    fn finish(&mut self) -> Result<()>;
+}
+
+impl<K: VideoSink + ?Sized> VideoSink for Box<K> {
+    fn write_frame(&mut self, frame: &DynamicImage) -> Result<()> {
+        (**self).write_frame(frame)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+fn is_y4m(path: &Path) -> bool {
+    path.extension().map(|ext| ext.eq_ignore_ascii_case("y4m")).unwrap_or(false)
+}
+
+// .y4m files are read directly, anything else through ffmpeg
+pub fn open_video(path: &Path) -> Result<Box<dyn FrameSource + Send>> {
+    if is_y4m(path) {
+        let file = File::open(path).map_err(|source| BodyPixError::MissingFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Box::new(Y4mReader::new(file)?))
+    } else {
+        Ok(Box::new(FfmpegSource::open(path)?))
+    }
+}
+
+// .y4m files are written directly (with alpha as a separate luma-only matte
+// track), anything else through ffmpeg
+pub fn create_video(path: &Path, frame_rate: (u32, u32), alpha: bool) -> Result<Box<dyn VideoSink>> {
+    if is_y4m(path) {
+        let file = File::create(path).map_err(BodyPixError::Io)?;
+        let colour = if alpha { Y4mColour::Mono } else { Y4mColour::C420 };
+        Ok(Box::new(Y4mWriter::new(BufWriter::new(file), frame_rate, colour)))
+    } else {
+        Ok(Box::new(FfmpegSink::create(path, frame_rate, alpha)?))
+    }
+}
+
+// Segments every frame of a source, handing each frame and its mask to a
+// callback. Returns the number of frames processed.
+pub fn for_each_segmented_frame<S, F>(source: &mut S, body_pix: &BodyPix, mut handle: F) -> Result<usize>
+where
+    S: FrameSource + ?Sized,
+    F: FnMut(&DynamicImage, &Segments) -> Result<()>,
+{
+    let mut frames = 0;
+    while let Some(frame) = source.next_frame()? {
+        let segments = body_pix.process_image(&frame)?;
+        handle(&frame, &segments)?;
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+// Applies an effect, like the ones in image_utils, to every frame of a video
+pub fn process_video<S, K, F>(source: &mut S, sink: &mut K, body_pix: &BodyPix, mut effect: F) -> Result<usize>
+where
+    S: FrameSource + ?Sized,
+    K: VideoSink + ?Sized,
+    F: FnMut(&Segments, &DynamicImage) -> DynamicImage,
+{
+    let frames = for_each_segmented_frame(source, body_pix, |frame, segments| {
+        sink.write_frame(&effect(segments, frame))
+    })?;
+    sink.finish()?;
+    Ok(frames)
+}
+
+// Picks a source from a description, so a camera can be swapped for a
+// recording on machines without one:
+// - a directory: the images in it, looping
+// - a .yuv file: raw YUYV frames of the given size, looping
+// - anything else: a V4L2 device, like /dev/video0
+pub fn open_source(spec: &str, width: u32, height: u32) -> Result<Box<dyn FrameSource + Send>> {
+    let path = Path::new(spec);
+    if path.is_dir() {
+        return Ok(Box::new(ImageSequence::from_dir(path, true)?));
+    }
+    if path.extension().map(|ext| ext == "yuv").unwrap_or(false) {
+        return Ok(Box::new(RawVideoFile::open(path, width, height, PixelFormat::Yuyv, true)?));
+    }
+
+    #[cfg(all(feature = "v4l2", target_os = "linux"))]
+    {
+        Ok(Box::new(V4l2Camera::open(path, width, height)?))
+    }
+    #[cfg(not(all(feature = "v4l2", target_os = "linux")))]
+    {
+        Err(BodyPixError::UnsupportedImage(format!(
+            "\"{}\" is not a directory or .yuv file, and camera support needs the \"v4l2\" feature",
+            spec
+        )))
+    }
+}
+
+pub struct LiveFrame {
+    pub original: DynamicImage,
+    pub segments: Segments,
+    pub mask: Option<Mask>, // Smoothed over the frames before, with a temporal filter
+    pub composited: DynamicImage,
+}
+
+// Reads, segments and composites frames on a worker thread, as fast as the
+// model allows. At most one processed frame waits to be collected, so a slow
+// consumer holds the worker back instead of building up latency.
+pub struct LivePipeline {
+    receiver: Receiver<Result<LiveFrame>>,
+    stop: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    worker: Option<JoinHandle<BodyPix>>,
+}
+
+impl LivePipeline {
+    pub fn start<S>(source: S, body_pix: BodyPix, compositor: Compositor) -> LivePipeline
+    where
+        S: FrameSource + Send + 'static,
+    {
+        LivePipeline::start_with(source, body_pix, compositor, None)
+    }
+
+    // Smooths the masks over consecutive frames, which keeps the edges of
+    // people from flickering
+    pub fn start_with_filter<S>(
+        source: S,
+        body_pix: BodyPix,
+        compositor: Compositor,
+        filter: TemporalFilter,
+    ) -> LivePipeline
+    where
+        S: FrameSource + Send + 'static,
+    {
+        LivePipeline::start_with(source, body_pix, compositor, Some(filter))
+    }
+
+    fn start_with<S>(
+        mut source: S,
+        body_pix: BodyPix,
+        mut compositor: Compositor,
+        mut filter: Option<TemporalFilter>,
+    ) -> LivePipeline
+    where
+        S: FrameSource + Send + 'static,
+    {
+        let (sender, receiver) = sync_channel(1);
+        let stop = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        let worker_finished = finished.clone();
+
+        let worker = std::thread::spawn(move || {
+            while !worker_stop.load(Ordering::Relaxed) {
+                let frame = match source.next_frame() {
+                    Ok(Some(original)) => body_pix.process_image(&original).map(|segments| {
+                        let mask = filter.as_mut().map(|filter| filter.filter(&segments, &original));
+                        let composited = match &mask {
+                            Some(mask) => compositor.composite(mask, &original),
+                            None => compositor.composite(&segments, &original),
+                        };
+                        LiveFrame {
+                            original,
+                            segments,
+                            mask,
+                            composited,
+                        }
+                    }),
+                    Ok(None) => break,
+                    Err(err) => Err(err),
+                };
+
+                let is_err = frame.is_err();
+                if sender.send(frame).is_err() || is_err {
+                    break;
+                }
+            }
+            worker_finished.store(true, Ordering::Relaxed);
+            body_pix
+        });
+
+        LivePipeline {
+            receiver,
+            stop,
+            finished,
+            worker: Some(worker),
+        }
+    }
+
+    // The newest processed frame, if there is one since the last call
+    pub fn try_latest(&self) -> Option<Result<LiveFrame>> {
+        let mut latest = None;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(frame) => latest = Some(frame),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return latest,
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.finished.load(Ordering::Relaxed)
+    }
+
+    // Stops the worker and hands back the model
+    pub fn stop(mut self) -> Option<BodyPix> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Option<BodyPix> {
+        self.stop.store(true, Ordering::Relaxed);
+        // Unblock a worker waiting to send
+        while self.receiver.try_recv().is_ok() {}
+        self.worker.take().and_then(|worker| worker.join().ok())
+    }
+}
+
+impl Drop for LivePipeline {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageFormat};
+
+use crate::error::{BodyPixError, Result};
+use crate::video::yuv::{to_rgb, PixelFormat};
+use crate::video::FrameSource;
+
+// Still images played back as frames, in file name order
+pub struct ImageSequence {
+    paths: Vec<PathBuf>,
+    index: usize,
+    looping: bool,
+}
+
+impl ImageSequence {
+    pub fn new(paths: Vec<PathBuf>, looping: bool) -> ImageSequence {
+        ImageSequence {
+            paths,
+            index: 0,
+            looping,
+        }
+    }
+
+    pub fn from_dir(dir: &Path, looping: bool) -> Result<ImageSequence> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(BodyPixError::Io)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            // Only images, so a README or .DS_Store doesn't stop the sequence
+            .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok_and(|format| format.can_read()))
+            .collect();
+        paths.sort();
+
+        Ok(ImageSequence::new(paths, looping))
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        if self.index >= self.paths.len() {
+            if !self.looping || self.paths.is_empty() {
+                return Ok(None);
+            }
+            self.index = 0;
+        }
+
+        let path = &self.paths[self.index];
+        self.index += 1;
+        let image = ImageReader::open(path)
+            .map_err(BodyPixError::Io)?
+            .decode()?;
+
+        Ok(Some(image))
+    }
+}
+
+// Headerless frames of a fixed size and pixel format, back to back, like the
+// output of `ffmpeg -f rawvideo` or a dump from a V4L2 device
+pub struct RawVideoFile {
+    reader: BufReader<File>,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    looping: bool,
+    buffer: Vec<u8>,
+}
+
+impl RawVideoFile {
+    pub fn open(path: &Path, width: u32, height: u32, format: PixelFormat, looping: bool) -> Result<RawVideoFile> {
+        let file = File::open(path).map_err(|source| BodyPixError::MissingFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        Ok(RawVideoFile {
+            reader: BufReader::new(file),
+            width,
+            height,
+            format,
+            looping,
+            buffer: vec![0u8; format.frame_size(width, height)],
+        })
+    }
+}
+
+impl FrameSource for RawVideoFile {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        match self.reader.read_exact(&mut self.buffer) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                if !self.looping {
+                    return Ok(None);
+                }
+                self.reader.seek(SeekFrom::Start(0)).map_err(BodyPixError::Io)?;
+                self.reader.read_exact(&mut self.buffer).map_err(BodyPixError::Io)?;
+            }
+            Err(err) => return Err(BodyPixError::Io(err)),
+        }
+
+        let img = to_rgb(&self.buffer, self.width, self.height, self.format)?;
+        Ok(Some(DynamicImage::ImageRgb8(img)))
+    }
+}
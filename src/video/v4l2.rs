@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use image::DynamicImage;
+use v4l::buffer::Type;
+use v4l::io::traits::CaptureStream;
+use v4l::prelude::*;
+use v4l::video::Capture;
+use v4l::FourCC;
+
+use crate::error::{BodyPixError, Result};
+use crate::video::yuv::{to_rgb, PixelFormat};
+use crate::video::FrameSource;
+
+// A camera, through Video4Linux2 memory mapped streaming
+pub struct V4l2Camera {
+    stream: MmapStream<'static>,
+    width: u32,
+    height: u32,
+    fourcc: FourCC,
+    _device: Device,
+}
+
+impl V4l2Camera {
+    // Asks for YUYV at the given size. The driver may pick another size or
+    // format, which is used as long as it is one we can decode.
+    pub fn open(path: &Path, width: u32, height: u32) -> Result<V4l2Camera> {
+        let device = Device::with_path(path).map_err(BodyPixError::Io)?;
+
+        let mut format = device.format().map_err(BodyPixError::Io)?;
+        format.width = width;
+        format.height = height;
+        format.fourcc = FourCC::new(b"YUYV");
+        let format = device.set_format(&format).map_err(BodyPixError::Io)?;
+
+        if ![b"YUYV", b"RGB3", b"MJPG"].iter().any(|code| format.fourcc == FourCC::new(code)) {
+            return Err(BodyPixError::UnsupportedImage(format!(
+                "camera pixel format {} is not supported",
+                format.fourcc
+            )));
+        }
+
+        let stream = MmapStream::with_buffers(&device, Type::VideoCapture, 4).map_err(BodyPixError::Io)?;
+
+        Ok(V4l2Camera {
+            stream,
+            width: format.width,
+            height: format.height,
+            fourcc: format.fourcc,
+            _device: device,
+        })
+    }
+}
+
+impl FrameSource for V4l2Camera {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        let (data, _) = self.stream.next().map_err(BodyPixError::Io)?;
+
+        let image = if self.fourcc == FourCC::new(b"MJPG") {
+            image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)?
+        } else {
+            let format = if self.fourcc == FourCC::new(b"YUYV") {
+                PixelFormat::Yuyv
+            } else {
+                PixelFormat::Rgb24
+            };
+            DynamicImage::ImageRgb8(to_rgb(data, self.width, self.height, format)?)
+        };
+
+        Ok(Some(image))
+    }
+}
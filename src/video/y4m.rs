@@ -0,0 +1,251 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+use crate::error::{BodyPixError, Result};
+use crate::video::yuv::{to_i420, to_rgb, PixelFormat};
+use crate::video::{FrameSource, VideoSink};
+
+fn invalid(msg: String) -> BodyPixError {
+    BodyPixError::UnsupportedImage(format!("Invalid Y4M stream: {}", msg))
+}
+
+// Reads an uncompressed YUV4MPEG2 stream, as written by `ffmpeg -f yuv4mpegpipe`
+pub struct Y4mReader<R: Read> {
+    reader: BufReader<R>,
+    width: u32,
+    height: u32,
+    frame_rate: (u32, u32),
+    format: PixelFormat,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> Y4mReader<R> {
+    pub fn new(reader: R) -> Result<Y4mReader<R>> {
+        let mut reader = BufReader::new(reader);
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(BodyPixError::Io)?;
+
+        let mut params = header.trim_end().split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(invalid("missing YUV4MPEG2 signature".to_string()));
+        }
+
+        let (mut width, mut height) = (0, 0);
+        let mut frame_rate = (30, 1);
+        let mut format = PixelFormat::I420;
+        for param in params.filter(|param| !param.is_empty()) {
+            let (tag, value) = param.split_at(1);
+            match tag {
+                "W" => width = value.parse().map_err(|_| invalid(format!("bad width {}", value)))?,
+                "H" => height = value.parse().map_err(|_| invalid(format!("bad height {}", value)))?,
+                "F" => {
+                    let mut parts = value.split(':').map(str::parse::<u32>);
+                    if let (Some(Ok(num)), Some(Ok(den))) = (parts.next(), parts.next()) {
+                        frame_rate = (num, den);
+                    }
+                }
+                "C" => {
+                    // Only 8-bit samples; the 420 variants differ in chroma siting
+                    format = match value {
+                        "420" | "420jpeg" | "420mpeg2" | "420paldv" => PixelFormat::I420,
+                        "444" => PixelFormat::I444,
+                        "mono" => PixelFormat::Gray8,
+                        _ => return Err(invalid(format!("unsupported colour space {}", value))),
+                    }
+                }
+                _ => {} // Interlacing, aspect ratio and extensions don't matter here
+            }
+        }
+
+        if width == 0 || height == 0 {
+            return Err(invalid("missing frame size".to_string()));
+        }
+
+        Ok(Y4mReader {
+            reader,
+            width,
+            height,
+            frame_rate,
+            format,
+            buffer: vec![0u8; format.frame_size(width, height)],
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl<R: Read> FrameSource for Y4mReader<R> {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        let mut frame_header = String::new();
+        if self.reader.read_line(&mut frame_header).map_err(BodyPixError::Io)? == 0 {
+            return Ok(None);
+        }
+        if !frame_header.starts_with("FRAME") {
+            return Err(invalid(format!("expected FRAME, got {:?}", frame_header.trim_end())));
+        }
+
+        self.reader.read_exact(&mut self.buffer).map_err(BodyPixError::Io)?;
+        let img = to_rgb(&self.buffer, self.width, self.height, self.format)?;
+        Ok(Some(DynamicImage::ImageRgb8(img)))
+    }
+
+    fn frame_rate(&self) -> Option<(u32, u32)> {
+        Some(self.frame_rate)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Y4mColour {
+    C420,
+    // Luma only, e.g. a matte track with the mask as full range values
+    Mono,
+}
+
+// Writes frames as YUV4MPEG2. The header goes out with the first frame, as
+// that is when the frame size is known.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    frame_rate: (u32, u32),
+    colour: Y4mColour,
+    dimensions: Option<(u32, u32)>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(writer: W, frame_rate: (u32, u32), colour: Y4mColour) -> Y4mWriter<W> {
+        Y4mWriter {
+            writer,
+            frame_rate,
+            colour,
+            dimensions: None,
+        }
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> Result<()> {
+        let colour = match self.colour {
+            Y4mColour::C420 => "420jpeg",
+            Y4mColour::Mono => "mono",
+        };
+        writeln!(
+            self.writer,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C{}",
+            width, height, self.frame_rate.0, self.frame_rate.1, colour
+        )
+        .map_err(BodyPixError::Io)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> VideoSink for Y4mWriter<W> {
+    fn write_frame(&mut self, frame: &DynamicImage) -> Result<()> {
+        let (width, height) = (frame.width(), frame.height());
+        match self.dimensions {
+            None => {
+                self.write_header(width, height)?;
+                self.dimensions = Some((width, height));
+            }
+            Some(dimensions) if dimensions != (width, height) => {
+                return Err(BodyPixError::UnsupportedImage(format!(
+                    "frame is {}x{}, but the stream is {}x{}",
+                    width, height, dimensions.0, dimensions.1
+                )));
+            }
+            Some(_) => {}
+        }
+
+        let data = match self.colour {
+            Y4mColour::C420 => to_i420(&frame.to_rgb8()),
+            Y4mColour::Mono => frame_luma(frame).into_raw(),
+        };
+
+        self.writer.write_all(b"FRAME\n").map_err(BodyPixError::Io)?;
+        self.writer.write_all(&data).map_err(BodyPixError::Io)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush().map_err(BodyPixError::Io)
+    }
+}
+
+// The alpha channel where there is one, so RGBA mattes become matte tracks
+fn frame_luma(frame: &DynamicImage) -> GrayImage {
+    match frame {
+        DynamicImage::ImageRgba8(rgba) => {
+            GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba.get_pixel(x, y)[3]]))
+        }
+        _ => frame.to_luma8(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    // One colour for each 2x2 block, which 4:2:0 keeps
+    fn frame(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(40 + x / 2 * 60) as u8, (60 + y / 2 * 80) as u8, 120])
+        }))
+    }
+
+    fn write(frames: &[DynamicImage], colour: Y4mColour) -> Vec<u8> {
+        let mut writer = Y4mWriter::new(Vec::new(), (25, 1), colour);
+        for frame in frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn colour_round_trip() {
+        // Odd sizes, so the chroma planes round up
+        let frames = [frame(5, 3), frame(5, 3)];
+        let data = write(&frames, Y4mColour::C420);
+        let mut reader = Y4mReader::new(&data[..]).unwrap();
+        assert_eq!(reader.dimensions(), (5, 3));
+        assert_eq!(reader.frame_rate(), Some((25, 1)));
+
+        for expected in &frames {
+            let actual = reader.next_frame().unwrap().unwrap().to_rgb8();
+            for (x, y, pixel) in expected.to_rgb8().enumerate_pixels() {
+                for c in 0..3 {
+                    let difference = (actual.get_pixel(x, y)[c] as i32 - pixel[c] as i32).abs();
+                    assert!(difference <= 4, "pixel {},{} channel {} is off by {}", x, y, c, difference);
+                }
+            }
+        }
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn mono_round_trip() {
+        let matte = GrayImage::from_fn(3, 2, |x, y| image::Luma([(x * 80 + y * 20) as u8]));
+        let data = write(&[DynamicImage::ImageLuma8(matte.clone())], Y4mColour::Mono);
+        let mut reader = Y4mReader::new(&data[..]).unwrap();
+        assert_eq!(reader.next_frame().unwrap().unwrap().to_luma8(), matte);
+    }
+
+    #[test]
+    fn rejects_frames_of_another_size() {
+        let mut writer = Y4mWriter::new(Vec::new(), (25, 1), Y4mColour::C420);
+        writer.write_frame(&frame(4, 4)).unwrap();
+        assert!(writer.write_frame(&frame(2, 2)).is_err());
+    }
+
+    #[test]
+    fn colour_spaces() {
+        let header = |colour: &str| format!("YUV4MPEG2 W2 H2 F25:1 C{}\n", colour);
+        assert!(Y4mReader::new(header("420paldv").as_bytes()).is_ok());
+        assert!(Y4mReader::new(header("mono").as_bytes()).is_ok());
+        // Only 8-bit samples are supported
+        assert!(Y4mReader::new(header("420p10").as_bytes()).is_err());
+        assert!(Y4mReader::new(header("422").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use image::RgbImage;
+
+use crate::error::{BodyPixError, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    // Packed 4:2:2, as most webcams deliver
+    Yuyv,
+    // Planar 4:2:0
+    I420,
+    // Planar 4:4:4
+    I444,
+    Gray8,
+    Rgb24,
+}
+
+impl PixelFormat {
+    pub fn frame_size(self, width: u32, height: u32) -> usize {
+        let (width, height) = (width as usize, height as usize);
+        match self {
+            PixelFormat::Yuyv => width * height * 2,
+            PixelFormat::I420 => width * height + 2 * width.div_ceil(2) * height.div_ceil(2),
+            PixelFormat::I444 | PixelFormat::Rgb24 => width * height * 3,
+            PixelFormat::Gray8 => width * height,
+        }
+    }
+}
+
+// BT.601, limited range
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = 1.164 * (y as f32 - 16.);
+    let u = u as f32 - 128.;
+    let v = v as f32 - 128.;
+    let clamp = |val: f32| val.round().clamp(0., 255.) as u8;
+    [
+        clamp(y + 1.596 * v),
+        clamp(y - 0.813 * v - 0.391 * u),
+        clamp(y + 2.018 * u),
+    ]
+}
+
+fn rgb_to_yuv(red: u8, green: u8, blue: u8) -> [u8; 3] {
+    let (red, green, blue) = (red as f32, green as f32, blue as f32);
+    let clamp = |val: f32| val.round().clamp(0., 255.) as u8;
+    [
+        clamp(16. + 0.257 * red + 0.504 * green + 0.098 * blue),
+        clamp(128. - 0.148 * red - 0.291 * green + 0.439 * blue),
+        clamp(128. + 0.439 * red - 0.368 * green - 0.071 * blue),
+    ]
+}
+
+pub fn to_rgb(data: &[u8], width: u32, height: u32, format: PixelFormat) -> Result<RgbImage> {
+    let expected = format.frame_size(width, height);
+    if data.len() < expected {
+        return Err(BodyPixError::UnsupportedImage(format!(
+            "{:?} frame of {}x{} needs {} bytes, got {}",
+            format,
+            width,
+            height,
+            expected,
+            data.len()
+        )));
+    }
+
+    let (w, h) = (width as usize, height as usize);
+    let img = match format {
+        PixelFormat::Yuyv => RgbImage::from_fn(width, height, |x, y| {
+            // Each pair of pixels shares one U and one V sample
+            let pair = (y as usize * w + (x as usize & !1)) * 2;
+            let luma = data[pair + (x as usize & 1) * 2];
+            image::Rgb(yuv_to_rgb(luma, data[pair + 1], data[pair + 3]))
+        }),
+        PixelFormat::I420 => {
+            let chroma_width = w.div_ceil(2);
+            let u_plane = &data[w * h..];
+            let v_plane = &u_plane[chroma_width * h.div_ceil(2)..];
+            RgbImage::from_fn(width, height, |x, y| {
+                let (x, y) = (x as usize, y as usize);
+                let chroma = (y / 2) * chroma_width + x / 2;
+                image::Rgb(yuv_to_rgb(data[y * w + x], u_plane[chroma], v_plane[chroma]))
+            })
+        }
+        PixelFormat::I444 => {
+            let (u_plane, v_plane) = (&data[w * h..], &data[2 * w * h..]);
+            RgbImage::from_fn(width, height, |x, y| {
+                let i = y as usize * w + x as usize;
+                image::Rgb(yuv_to_rgb(data[i], u_plane[i], v_plane[i]))
+            })
+        }
+        PixelFormat::Gray8 => RgbImage::from_fn(width, height, |x, y| {
+            let luma = data[y as usize * w + x as usize];
+            image::Rgb([luma, luma, luma])
+        }),
+        PixelFormat::Rgb24 => RgbImage::from_raw(width, height, data[..expected].to_vec()).unwrap(),
+    };
+
+    Ok(img)
+}
+
+// Planar 4:2:0, averaging the chroma of each 2x2 block
+pub(crate) fn to_i420(img: &RgbImage) -> Vec<u8> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let (chroma_width, chroma_height) = (w.div_ceil(2), h.div_ceil(2));
+    let mut data = vec![0u8; PixelFormat::I420.frame_size(img.width(), img.height())];
+    let (luma, chroma) = data.split_at_mut(w * h);
+    let (u_plane, v_plane) = chroma.split_at_mut(chroma_width * chroma_height);
+
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let [luma_val, _, _] = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+        luma[y as usize * w + x as usize] = luma_val;
+    }
+
+    for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+            let (mut u_sum, mut v_sum, mut count) = (0u32, 0u32, 0u32);
+            for y in (cy * 2)..std::cmp::min(cy * 2 + 2, h) {
+                for x in (cx * 2)..std::cmp::min(cx * 2 + 2, w) {
+                    let pixel = img.get_pixel(x as u32, y as u32);
+                    let [_, u, v] = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+                    u_sum += u as u32;
+                    v_sum += v as u32;
+                    count += 1;
+                }
+            }
+            u_plane[cy * chroma_width + cx] = (u_sum / count) as u8;
+            v_plane[cy * chroma_width + cx] = (v_sum / count) as u8;
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &RgbImage, expected: &RgbImage) {
+        assert_eq!(actual.dimensions(), expected.dimensions());
+        for (x, y, pixel) in expected.enumerate_pixels() {
+            let got = actual.get_pixel(x, y);
+            for c in 0..3 {
+                let difference = (got[c] as i32 - pixel[c] as i32).abs();
+                assert!(difference <= 4, "pixel {},{}: got {:?}, expected {:?}", x, y, got, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn i420_frame_size_rounds_chroma_up() {
+        assert_eq!(PixelFormat::I420.frame_size(4, 2), 8 + 2 * 2);
+        assert_eq!(PixelFormat::I420.frame_size(5, 3), 15 + 2 * 3 * 2);
+    }
+
+    #[test]
+    fn i420_round_trip_at_odd_size() {
+        // The last column and row have chroma blocks of their own
+        let (red, blue) = (image::Rgb([200, 40, 40]), image::Rgb([30, 60, 200]));
+        let img = RgbImage::from_fn(5, 3, |x, y| if x == 4 || y == 2 { red } else { blue });
+
+        let data = to_i420(&img);
+        assert_eq!(data.len(), PixelFormat::I420.frame_size(5, 3));
+        assert_close(&to_rgb(&data, 5, 3, PixelFormat::I420).unwrap(), &img);
+    }
+
+    #[test]
+    fn to_rgb_rejects_short_frames() {
+        let data = vec![0u8; PixelFormat::I420.frame_size(5, 3) - 1];
+        assert!(to_rgb(&data, 5, 3, PixelFormat::I420).is_err());
+    }
+}